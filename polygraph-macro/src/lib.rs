@@ -14,6 +14,14 @@ mod tests {
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn plural() {
+        assert_eq!(super::plural("person"), "persons");
+        assert_eq!(super::plural("address"), "addresses");
+        assert_eq!(super::plural("company"), "companies");
+        assert_eq!(super::plural("day"), "days");
+    }
 }
 
 enum Item {
@@ -41,13 +49,13 @@ impl syn::parse::Parse for Item {
                 Item::Struct(item) => (&mut item.vis, &mut item.attrs, &item.generics),
                 Item::Enum(item) => (&mut item.vis, &mut item.attrs, &item.generics),
             };
-            if generics.params.len() > 0 {
+            if !generics.params.is_empty() {
                 return Err(syn::Error::new_spanned(
                     generics,
                     "schema! does not support generic types.",
                 ));
             }
-            attrs.extend(item_attrs.drain(..));
+            attrs.append(item_attrs);
             *item_attrs = attrs;
             *item_vis = vis;
         }
@@ -58,6 +66,11 @@ impl syn::parse::Parse for Item {
 
 #[derive(Debug)]
 struct SchemaInput {
+    /// Attributes on the database type, other than `#[polygraph(...)]`.
+    attrs: Vec<syn::Attribute>,
+    /// Whether the keys of each database are distinct from those of every
+    /// other, as asked for by `#[polygraph(branded)]`.
+    branded: bool,
     name: syn::Ident,
     structs: Vec<syn::ItemStruct>,
     enums: Vec<syn::ItemEnum>,
@@ -65,6 +78,32 @@ struct SchemaInput {
 
 impl syn::parse::Parse for SchemaInput {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let mut attrs = Vec::new();
+        let mut branded = false;
+        for a in input.call(syn::Attribute::parse_outer)? {
+            if !a.path.is_ident("polygraph") {
+                attrs.push(a);
+                continue;
+            }
+            let list = if let syn::Meta::List(list) = a.parse_meta()? {
+                list
+            } else {
+                return Err(syn::Error::new_spanned(a, "expected #[polygraph(...)]"));
+            };
+            for nested in list.nested.iter() {
+                match nested {
+                    syn::NestedMeta::Meta(syn::Meta::Path(p)) if p.is_ident("branded") => {
+                        branded = true;
+                    }
+                    _ => {
+                        return Err(syn::Error::new_spanned(
+                            nested,
+                            "unknown polygraph attribute",
+                        ));
+                    }
+                }
+            }
+        }
         input.parse::<syn::Token![type]>()?;
         let name: syn::Ident = input.parse()?;
         input.parse::<syn::Token![;]>()?;
@@ -77,6 +116,8 @@ impl syn::parse::Parse for SchemaInput {
             }
         }
         Ok(SchemaInput {
+            attrs,
+            branded,
             name,
             structs,
             enums,
@@ -87,11 +128,74 @@ impl syn::parse::Parse for SchemaInput {
 #[derive(Debug)]
 struct SchemaOutput {
     // name: syn::Ident,
-    pod_structs: Vec<syn::ItemStruct>,
-    pod_enums: Vec<syn::ItemEnum>,
-    key_structs: Vec<syn::ItemStruct>,
-    key_struct_maps: Vec<std::collections::HashMap<syn::Ident, KeyType>>,
-    // key_enums: Vec<syn::ItemEnum>,
+    /// The tables (structs or enums) that hold no keys.
+    pod_structs: Vec<TableItem>,
+    /// The tables (structs or enums) that hold keys.
+    key_structs: Vec<TableItem>,
+    key_struct_maps: Vec<std::collections::HashMap<syn::Ident, KeyField>>,
+}
+
+/// A table, which is defined by either a struct or an enum.
+#[derive(Debug, Clone)]
+struct TableItem {
+    attrs: Vec<syn::Attribute>,
+    vis: syn::Visibility,
+    ident: syn::Ident,
+    generics: syn::Generics,
+    body: TableBody,
+}
+
+#[derive(Debug, Clone)]
+enum TableBody {
+    Struct(syn::Fields),
+    Enum(syn::punctuated::Punctuated<syn::Variant, syn::Token![,]>),
+}
+
+impl From<syn::ItemStruct> for TableItem {
+    fn from(x: syn::ItemStruct) -> Self {
+        TableItem {
+            attrs: x.attrs,
+            vis: x.vis,
+            ident: x.ident,
+            generics: x.generics,
+            body: TableBody::Struct(x.fields),
+        }
+    }
+}
+
+impl From<syn::ItemEnum> for TableItem {
+    fn from(x: syn::ItemEnum) -> Self {
+        TableItem {
+            attrs: x.attrs,
+            vis: x.vis,
+            ident: x.ident,
+            generics: x.generics,
+            body: TableBody::Enum(x.variants),
+        }
+    }
+}
+
+impl quote::ToTokens for TableItem {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let TableItem {
+            attrs,
+            vis,
+            ident,
+            generics,
+            ..
+        } = self;
+        tokens.extend(match &self.body {
+            TableBody::Struct(fields @ syn::Fields::Named(_)) => quote::quote! {
+                #(#attrs)* #vis struct #ident #generics #fields
+            },
+            TableBody::Struct(fields) => quote::quote! {
+                #(#attrs)* #vis struct #ident #generics #fields;
+            },
+            TableBody::Enum(variants) => quote::quote! {
+                #(#attrs)* #vis enum #ident #generics { #variants }
+            },
+        });
+    }
 }
 
 // fn lifetime_a() -> syn::Generics {
@@ -113,11 +217,15 @@ struct SchemaOutput {
 //     }
 // }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 enum KeyType {
     Key(syn::Ident),
     OptionKey(syn::Ident),
     KeySet(syn::Ident),
+    /// An ordered list of keys, which may hold the same key more than once.
+    KeyVec(syn::Ident),
+    /// A set of keys, each with a payload of the given type.
+    KeyMap(syn::Ident, Box<syn::Type>),
 }
 
 impl KeyType {
@@ -126,8 +234,190 @@ impl KeyType {
             KeyType::Key(i) => i.clone(),
             KeyType::OptionKey(i) => i.clone(),
             KeyType::KeySet(i) => i.clone(),
+            KeyType::KeyVec(i) => i.clone(),
+            KeyType::KeyMap(i, _) => i.clone(),
+        }
+    }
+
+    /// The type of a field of this type.
+    fn field_type(&self) -> proc_macro2::TokenStream {
+        match self {
+            KeyType::Key(t) => quote::quote! { Key<#t> },
+            KeyType::OptionKey(t) => quote::quote! { Option<Key<#t>> },
+            KeyType::KeySet(t) => quote::quote! { KeySet<#t> },
+            KeyType::KeyVec(t) => quote::quote! { Vec<Key<#t>> },
+            KeyType::KeyMap(t, v) => quote::quote! { KeyMap<#t, #v> },
+        }
+    }
+
+    /// Generates an expression iterating over every `Key` held in
+    /// `value`, which is a field of this type.
+    fn keys(&self, value: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+        match self {
+            KeyType::Key(_) => quote::quote! { std::iter::once(#value) },
+            KeyType::OptionKey(_) => quote::quote! { #value.into_iter() },
+            KeyType::KeySet(_) => quote::quote! { #value.iter() },
+            KeyType::KeyVec(_) => quote::quote! { #value.iter().copied() },
+            KeyType::KeyMap(..) => quote::quote! { #value.keys().copied() },
+        }
+    }
+}
+
+/// What happens to a row when a row it refers to is removed.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum OnDelete {
+    /// The referring row is removed too.
+    Cascade,
+    /// The field is set to `None`, or the key is removed from the `KeySet`.
+    SetNull,
+    /// The removal fails.
+    Restrict,
+}
+
+impl OnDelete {
+    fn parse(lit: &syn::LitStr) -> Result<Self, syn::Error> {
+        match lit.value().as_str() {
+            "cascade" => Ok(OnDelete::Cascade),
+            "set_null" => Ok(OnDelete::SetNull),
+            "restrict" => Ok(OnDelete::Restrict),
+            _ => Err(syn::Error::new_spanned(
+                lit,
+                "on_delete should be \"cascade\", \"set_null\" or \"restrict\"",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct KeyField {
+    kind: KeyType,
+    on_delete: OnDelete,
+    place: Place,
+}
+
+/// Where the rows of a table hold a relationship.
+#[derive(Debug, Clone)]
+enum Place {
+    /// A field of a struct.
+    Field(syn::Member),
+    /// For a table defined by an enum, each variant holding this
+    /// relationship along with the field of the variant that holds it.
+    Variants(Vec<(syn::Ident, syn::Member)>),
+}
+
+impl KeyField {
+    /// The field of a struct that holds this relationship, or `None` if
+    /// it is held by the variants of an enum.
+    fn member(&self) -> Option<&syn::Member> {
+        match &self.place {
+            Place::Field(m) => Some(m),
+            Place::Variants(_) => None,
+        }
+    }
+
+    /// Generates an expression iterating over every `Key` that
+    /// relationship `k` of `row` holds, where `row` is a row or something
+    /// that dereferences to one.
+    fn keys(&self, k: &syn::Ident, row: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+        match &self.place {
+            Place::Field(m) => self.kind.keys(quote::quote! { #row.#m }),
+            Place::Variants(_) => {
+                let method = keys_fn(k);
+                quote::quote! { #row.#method().into_iter() }
+            }
+        }
+    }
+
+    /// Generates code that runs `body` on the field of `row` (a row of
+    /// `table` that may be modified) that holds this relationship.  Within
+    /// `body`, `x` is a mutable reference to the field.
+    fn with_field_mut(
+        &self,
+        table: &syn::Ident,
+        row: proc_macro2::TokenStream,
+        body: proc_macro2::TokenStream,
+    ) -> proc_macro2::TokenStream {
+        match &self.place {
+            Place::Field(m) => quote::quote! {
+                {
+                    let x = &mut #row.#m;
+                    #body
+                }
+            },
+            Place::Variants(variants) => {
+                let arms = variants.iter().map(|(v, m)| {
+                    quote::quote! { #table::#v { #m: x, .. } => { #body } }
+                });
+                quote::quote! {
+                    #[allow(unreachable_patterns)]
+                    match &mut #row {
+                        #(#arms)*
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The name of the method generated for an enum table that lists the keys
+/// of one of its relationships, whichever variant a row is.
+fn keys_fn(k: &syn::Ident) -> syn::Ident {
+    quote::format_ident!("__{}_keys", k)
+}
+
+/// The options given by the `#[polygraph(...)]` attributes of a field,
+/// each along with the attribute itself (for error reporting).
+#[derive(Default)]
+struct FieldAttrs {
+    on_delete: Option<(OnDelete, syn::Attribute)>,
+    name: Option<(syn::Ident, syn::Attribute)>,
+}
+
+/// Strips any `#[polygraph(...)]` attributes from a field, returning the
+/// options they ask for.
+fn parse_field_attrs(attrs: &mut Vec<syn::Attribute>) -> Result<FieldAttrs, syn::Error> {
+    let mut out = FieldAttrs::default();
+    let mut kept = Vec::new();
+    for a in attrs.drain(..) {
+        if !a.path.is_ident("polygraph") {
+            kept.push(a);
+            continue;
+        }
+        let list = if let syn::Meta::List(list) = a.parse_meta()? {
+            list
+        } else {
+            return Err(syn::Error::new_spanned(a, "expected #[polygraph(...)]"));
+        };
+        for nested in list.nested.iter() {
+            match nested {
+                syn::NestedMeta::Meta(syn::Meta::NameValue(nv))
+                    if nv.path.is_ident("on_delete") =>
+                {
+                    if let syn::Lit::Str(lit) = &nv.lit {
+                        out.on_delete = Some((OnDelete::parse(lit)?, a.clone()));
+                    } else {
+                        return Err(syn::Error::new_spanned(&nv.lit, "expected a string"));
+                    }
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident("name") => {
+                    if let syn::Lit::Str(lit) = &nv.lit {
+                        out.name = Some((lit.parse()?, a.clone()));
+                    } else {
+                        return Err(syn::Error::new_spanned(&nv.lit, "expected a string"));
+                    }
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        nested,
+                        "unknown polygraph attribute",
+                    ));
+                }
+            }
         }
     }
+    *attrs = kept;
+    Ok(out)
 }
 
 fn first_of_type(t: &syn::Type) -> Option<(syn::Ident, syn::Type)> {
@@ -159,6 +449,65 @@ fn first_of_type(t: &syn::Type) -> Option<(syn::Ident, syn::Type)> {
     Some((ident, t.clone()))
 }
 
+/// The two type arguments of a `KeyMap`, if this is one.
+fn keymap_types(t: &syn::Type) -> Option<(syn::Type, syn::Type)> {
+    let p = if let syn::Type::Path(p) = t {
+        p
+    } else {
+        return None;
+    };
+    if p.path.segments.len() != 1 || p.path.segments[0].ident != "KeyMap" {
+        return None;
+    }
+    let args = if let syn::PathArguments::AngleBracketed(args) = &p.path.segments[0].arguments {
+        args
+    } else {
+        return None;
+    };
+    let mut types = args.args.iter().filter_map(|a| match a {
+        syn::GenericArgument::Type(t) => Some(t.clone()),
+        _ => None,
+    });
+    match (types.next(), types.next(), types.next()) {
+        (Some(key), Some(payload), None) if args.args.len() == 2 => Some((key, payload)),
+        _ => None,
+    }
+}
+
+/// Finds a `Key`, `KeySet` or `KeyMap` anywhere within a type.
+fn nested_key(t: &syn::Type) -> Option<&syn::PathSegment> {
+    match t {
+        syn::Type::Path(p) => {
+            if let Some(q) = &p.qself {
+                if let Some(found) = nested_key(&q.ty) {
+                    return Some(found);
+                }
+            }
+            p.path.segments.iter().find_map(|seg| {
+                if seg.ident == "Key" || seg.ident == "KeySet" || seg.ident == "KeyMap" {
+                    return Some(seg);
+                }
+                if let syn::PathArguments::AngleBracketed(args) = &seg.arguments {
+                    args.args.iter().find_map(|a| match a {
+                        syn::GenericArgument::Type(t) => nested_key(t),
+                        _ => None,
+                    })
+                } else {
+                    None
+                }
+            })
+        }
+        syn::Type::Array(a) => nested_key(&a.elem),
+        syn::Type::Group(g) => nested_key(&g.elem),
+        syn::Type::Paren(p) => nested_key(&p.elem),
+        syn::Type::Ptr(p) => nested_key(&p.elem),
+        syn::Type::Reference(r) => nested_key(&r.elem),
+        syn::Type::Slice(s) => nested_key(&s.elem),
+        syn::Type::Tuple(t) => t.elems.iter().find_map(nested_key),
+        _ => None,
+    }
+}
+
 fn type_is_just_ident(t: &syn::Type) -> Option<syn::Ident> {
     let p = if let syn::Type::Path(p) = t {
         p
@@ -178,12 +527,26 @@ fn type_is_just_ident(t: &syn::Type) -> Option<syn::Ident> {
 }
 
 fn parse_keytype(t: &syn::Type) -> Result<Option<KeyType>, syn::Error> {
-    if let Some((key, t)) = first_of_type(&t) {
-        if key.to_string() == "Option" {
-            if let Some((key, t)) = first_of_type(&t) {
-                if key.to_string() == "Key" {
+    if let Some((key, payload)) = keymap_types(t) {
+        if let Some(i) = type_is_just_ident(&key) {
+            return Ok(Some(KeyType::KeyMap(i, Box::new(payload))));
+        } else {
+            return Err(syn::Error::new_spanned(
+                key,
+                "Key type should be a simple table name",
+            ));
+        }
+    }
+    if let Some((key, t)) = first_of_type(t) {
+        if key == "Option" || key == "Vec" {
+            if let Some((inner, t)) = first_of_type(&t) {
+                if inner == "Key" {
                     if let Some(i) = type_is_just_ident(&t) {
-                        return Ok(Some(KeyType::OptionKey(i)));
+                        return Ok(Some(if key == "Option" {
+                            KeyType::OptionKey(i)
+                        } else {
+                            KeyType::KeyVec(i)
+                        }));
                     } else {
                         return Err(syn::Error::new_spanned(
                             t,
@@ -192,7 +555,7 @@ fn parse_keytype(t: &syn::Type) -> Result<Option<KeyType>, syn::Error> {
                     }
                 }
             }
-        } else if key.to_string() == "KeySet" {
+        } else if key == "KeySet" {
             if let Some(i) = type_is_just_ident(&t) {
                 return Ok(Some(KeyType::KeySet(i)));
             } else {
@@ -213,9 +576,8 @@ fn parse_keytype(t: &syn::Type) -> Result<Option<KeyType>, syn::Error> {
             let name = ident.to_string();
             // println!("path_only is {:#?}", name);
             if name == "Option" {
-                let args = path_only.clone().arguments;
-                println!("args are {:#?}", args);
-                unimplemented!()
+                // An Option of anything other than a Key is plain data.
+                Ok(None)
             } else {
                 if name == "Key" {
                     if let syn::PathArguments::AngleBracketed(args) = &path_only.arguments {
@@ -268,18 +630,212 @@ fn parse_keytype(t: &syn::Type) -> Result<Option<KeyType>, syn::Error> {
     }
 }
 
+/// Finds the relationship held by field `i` of a struct or variant, if
+/// any, stripping its `#[polygraph(...)]` attributes.  The relationship of
+/// a named field has the same name, while that of an unnamed field is
+/// named by a `name` attribute, or else by `prefix` and the position of the
+/// field.
+fn parse_field(
+    n: &mut syn::Field,
+    i: usize,
+    prefix: &str,
+) -> Result<Option<(syn::Ident, KeyField)>, syn::Error> {
+    let FieldAttrs { on_delete, name } = parse_field_attrs(&mut n.attrs)?;
+    // A key we do not know how to find would have no reverse index, and
+    // would be left dangling when its row is removed.
+    let nested = match parse_keytype(&n.ty)? {
+        None => nested_key(&n.ty).cloned(),
+        Some(KeyType::KeyMap(_, payload)) => nested_key(&payload).cloned(),
+        Some(_) => None,
+    };
+    if let Some(nested) = nested {
+        return Err(syn::Error::new_spanned(
+            nested,
+            "keys can only be held directly in a field of type Key, Option<Key>, \
+             KeySet, Vec<Key> or KeyMap, not nested inside another type",
+        ));
+    }
+    let (name, member) = match (&n.ident, name) {
+        (Some(_), Some((_, a))) => {
+            return Err(syn::Error::new_spanned(
+                a,
+                "name only applies to fields without names",
+            ));
+        }
+        (Some(ident), None) => (ident.clone(), syn::Member::Named(ident.clone())),
+        (None, name) => {
+            let member = syn::Member::Unnamed(syn::Index {
+                index: i as u32,
+                span: n.span(),
+            });
+            if let Some((name, a)) = name {
+                if parse_keytype(&n.ty)?.is_none() {
+                    return Err(syn::Error::new_spanned(
+                        a,
+                        "name only applies to fields that hold keys",
+                    ));
+                }
+                (name, member)
+            } else {
+                (quote::format_ident!("{}_{}", prefix, i), member)
+            }
+        }
+    };
+    if let Some(kind) = parse_keytype(&n.ty)? {
+        let on_delete = match (on_delete, &kind) {
+            (Some((OnDelete::SetNull, a)), KeyType::Key(_)) => {
+                return Err(syn::Error::new_spanned(
+                    a,
+                    "on_delete = \"set_null\" needs an Option<Key> or a KeySet",
+                ));
+            }
+            (Some((on_delete, _)), _) => on_delete,
+            (None, KeyType::Key(_)) => OnDelete::Restrict,
+            (None, _) => OnDelete::SetNull,
+        };
+        Ok(Some((
+            name,
+            KeyField {
+                kind,
+                on_delete,
+                place: Place::Field(member),
+            },
+        )))
+    } else if let Some((_, a)) = on_delete {
+        Err(syn::Error::new_spanned(
+            a,
+            "on_delete only applies to fields that hold keys",
+        ))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Finds the relationships held by the fields of a struct.  A field of a
+/// tuple struct is named after the struct and its position, unless a
+/// `name` attribute says otherwise.
 fn parse_fields(
-    f: &syn::FieldsNamed,
-) -> Result<std::collections::HashMap<syn::Ident, KeyType>, syn::Error> {
+    table: &syn::Ident,
+    f: &mut syn::Fields,
+) -> Result<std::collections::HashMap<syn::Ident, KeyField>, syn::Error> {
+    use heck::SnakeCase;
+    let prefix = table.to_string().to_snake_case();
     let mut keymap = std::collections::HashMap::new();
-    for n in f.named.iter() {
-        if let Some(kt) = parse_keytype(&n.ty)? {
-            keymap.insert(n.ident.clone().unwrap(), kt);
+    for (i, n) in f.iter_mut().enumerate() {
+        if let Some((name, field)) = parse_field(n, i, &prefix)? {
+            if keymap.insert(name, field).is_some() {
+                return Err(syn::Error::new_spanned(
+                    n,
+                    "another field has a relationship of this name",
+                ));
+            }
+        }
+    }
+    Ok(keymap)
+}
+
+/// Finds the relationships held by the variants of an enum.  A named field
+/// is the relationship of that name, which may be held by several
+/// variants, while a field of a tuple variant is named after the variant
+/// and its position, unless a `name` attribute says otherwise.
+fn parse_variants(
+    variants: &mut syn::punctuated::Punctuated<syn::Variant, syn::Token![,]>,
+) -> Result<std::collections::HashMap<syn::Ident, KeyField>, syn::Error> {
+    use heck::SnakeCase;
+    let mut keymap: std::collections::HashMap<syn::Ident, KeyField> =
+        std::collections::HashMap::new();
+    for v in variants.iter_mut() {
+        let vname = v.ident.to_string().to_snake_case();
+        for (i, n) in v.fields.iter_mut().enumerate() {
+            let (name, mut field) = if let Some(field) = parse_field(n, i, &vname)? {
+                field
+            } else {
+                continue;
+            };
+            let place = (v.ident.clone(), field.member().unwrap().clone());
+            if let Some(existing) = keymap.get_mut(&name) {
+                if existing.kind != field.kind || existing.on_delete != field.on_delete {
+                    return Err(syn::Error::new_spanned(
+                        &n.ty,
+                        "every field of this name must have the same type and on_delete",
+                    ));
+                }
+                if let Place::Variants(variants) = &mut existing.place {
+                    if variants.iter().any(|(v, _)| *v == place.0) {
+                        return Err(syn::Error::new_spanned(
+                            n,
+                            "another field has a relationship of this name",
+                        ));
+                    }
+                    variants.push(place);
+                }
+            } else {
+                field.place = Place::Variants(vec![place]);
+                keymap.insert(name, field);
+            }
         }
     }
     Ok(keymap)
 }
 
+/// The plural of a snake case table name, for naming methods that iterate
+/// over every row.
+fn plural(name: &str) -> String {
+    let consonant_y =
+        name.ends_with('y') && !name[..name.len() - 1].ends_with(|c| "aeiou".contains(c));
+    if consonant_y {
+        format!("{}ies", &name[..name.len() - 1])
+    } else if ["s", "x", "z", "ch", "sh"]
+        .iter()
+        .any(|e| name.ends_with(e))
+    {
+        format!("{}es", name)
+    } else {
+        format!("{}s", name)
+    }
+}
+
+/// Generates code that keeps the reverse `*_of` sets of table `t` up to
+/// date when relationship `k` of the row with key `k` changes from holding
+/// the keys iterated by `old` to those iterated by `new`.  A `None` for
+/// `old` means the row is new, and a `None` for `new` means the row is going
+/// away.
+fn backref_diff(
+    k: &syn::Ident,
+    t: &syn::Ident,
+    old: Option<proc_macro2::TokenStream>,
+    new: Option<proc_macro2::TokenStream>,
+) -> proc_macro2::TokenStream {
+    use heck::SnakeCase;
+    let field = quote::format_ident!("{}", t.to_string().to_snake_case());
+    let rev = quote::format_ident!("{}_of", k.to_string().to_snake_case());
+    match (old, new) {
+        (None, None) => quote::quote! {},
+        (None, Some(new)) => quote::quote! {
+            for idxk in #new {
+                self.#field[idxk.0].#rev.insert(k);
+            }
+        },
+        (Some(old), None) => quote::quote! {
+            for idxk in #old.collect::<Vec<_>>() {
+                self.#field[idxk.0].#rev.remove(&k);
+            }
+        },
+        (Some(old), Some(new)) => quote::quote! {
+            {
+                let old_keys: KeySet<#t> = #old.collect();
+                let new_keys: KeySet<#t> = #new.collect();
+                for idxk in old_keys.iter().filter(|x| !new_keys.contains(x)) {
+                    self.#field[idxk.0].#rev.remove(&k);
+                }
+                for idxk in new_keys.iter().filter(|x| !old_keys.contains(x)) {
+                    self.#field[idxk.0].#rev.insert(k);
+                }
+            }
+        },
+    }
+}
+
 impl SchemaInput {
     fn process(&self) -> Result<SchemaOutput, syn::Error> {
         let mut tables = std::collections::HashSet::new();
@@ -290,44 +846,33 @@ impl SchemaInput {
         let mut key_structs = Vec::new();
         let mut key_struct_maps = Vec::new();
 
-        for x in self.structs.iter().cloned() {
-            match &x.fields {
-                syn::Fields::Named(n) => {
-                    let keymap = parse_fields(n)?;
-                    if keymap.len() > 0 {
-                        key_struct_maps.push(keymap);
-                        key_structs.push(x);
-                    } else {
-                        pod_structs.push(x);
-                    }
-                }
-                syn::Fields::Unnamed(_) => {
-                    pod_structs.push(x);
-                }
-                syn::Fields::Unit => {
-                    pod_structs.push(x);
-                }
+        for mut x in self.structs.iter().cloned() {
+            let keymap = parse_fields(&x.ident, &mut x.fields)?;
+            if !keymap.is_empty() {
+                key_struct_maps.push(keymap);
+                key_structs.push(x.into());
+            } else {
+                pod_structs.push(x.into());
             }
         }
 
-        let pod_enums: Vec<_> = self
-            .enums
-            .iter()
-            .map(|x| {
-                let mut x = x.clone();
-                x.vis = syn::Visibility::Public(syn::VisPublic {
-                    pub_token: syn::Token!(pub)(x.span()),
-                });
-                x
-            })
-            .collect();
+        for mut x in self.enums.iter().cloned() {
+            x.vis = syn::Visibility::Public(syn::VisPublic {
+                pub_token: syn::Token!(pub)(x.span()),
+            });
+            let keymap = parse_variants(&mut x.variants)?;
+            if !keymap.is_empty() {
+                key_struct_maps.push(keymap);
+                key_structs.push(x.into());
+            } else {
+                pod_structs.push(x.into());
+            }
+        }
         Ok(SchemaOutput {
             // name: self.name.clone(),
             pod_structs,
             key_structs,
             key_struct_maps,
-            // key_enums: Vec::new(),
-            pod_enums,
         })
     }
 }
@@ -364,21 +909,23 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let mut reverse_references = std::collections::HashMap::new();
     for (map, t) in output.key_struct_maps.iter().zip(key_structs.iter()) {
         // println!("hello we have {:?}", t);
-        for (k, v) in map.iter() {
-            let kt = v.key_to();
+        let mut keys_and_types = map.iter().collect::<Vec<_>>();
+        keys_and_types.sort_by_key(|a| a.0);
+        for (k, v) in keys_and_types.into_iter() {
+            let kt = v.kind.key_to();
             if !reverse_references.contains_key(&kt) {
                 reverse_references.insert(kt.clone(), Vec::new());
             }
             reverse_references
                 .get_mut(&kt)
                 .unwrap()
-                .push((t.ident.clone(), k.clone()));
+                .push((t.ident.clone(), k.clone(), v.clone()));
         }
     }
     // println!("\n\nreverse references are {:?}", reverse_references);
 
     let mut pod_query_backrefs: Vec<Vec<(syn::Ident, syn::Ident)>> = Vec::new();
-    let pod_query_structs: Vec<syn::ItemStruct> = pod_structs
+    let pod_query_structs: Vec<TableItem> = pod_structs
         .iter()
         .cloned()
         .map(|mut x| {
@@ -399,10 +946,10 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
             }
             pod_query_backrefs.push(backrefs);
             x.ident = quote::format_ident!("{}Query", x.ident);
-            x.fields = syn::Fields::Named(syn::parse_quote! {{
+            x.body = TableBody::Struct(syn::Fields::Named(syn::parse_quote! {{
                 __data: #i,
                 #(#backrefs_code)*
-            }});
+            }}));
             x
         })
         .collect();
@@ -440,16 +987,20 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
         .iter()
         .map(|x| quote::format_ident!("insert_{}", x.ident.to_string().to_snake_case()))
         .collect();
+    let pod_insert_duplicates: Vec<_> = pod_structs
+        .iter()
+        .map(|x| quote::format_ident!("insert_duplicate_{}", x.ident.to_string().to_snake_case()))
+        .collect();
     let pod_lookups: Vec<_> = pod_structs
         .iter()
         // only allow lookups on non-generic fields
-        .filter(|x| x.generics.params.len() == 0)
+        .filter(|x| x.generics.params.is_empty())
         .map(|x| quote::format_ident!("lookup_{}", x.ident.to_string().to_snake_case()))
         .collect();
     let pod_lookup_hashes: Vec<_> = pod_structs
         .iter()
         // only allow lookups on non-generic fields
-        .filter(|x| x.generics.params.len() == 0)
+        .filter(|x| x.generics.params.is_empty())
         .map(|x| quote::format_ident!("hash_{}", x.ident.to_string().to_snake_case()))
         .collect();
     let pod_types: Vec<syn::PathSegment> = pod_structs
@@ -482,10 +1033,10 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
             }
             key_query_backrefs.push(backrefs);
             x.ident = quote::format_ident!("{}Query", x.ident);
-            x.fields = syn::Fields::Named(syn::parse_quote! {{
+            x.body = TableBody::Struct(syn::Fields::Named(syn::parse_quote! {{
                 __data: #i,
                 #(#backrefs_code)*
-            }});
+            }}));
             x
         })
         .collect();
@@ -497,6 +1048,24 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
             syn::parse_quote! {#i#g}
         })
         .collect();
+    let key_query_new: Vec<_> = key_query_structs
+        .iter()
+        .zip(key_query_backrefs.iter())
+        .map(|(x, br)| {
+            let i = &x.ident;
+            let backcode = br.iter().map(|(t, f)| {
+                quote::quote! {
+                    #f: KeySet::<#t>::new(),
+                }
+            });
+            quote::quote! {
+                #i {
+                    __data: value,
+                    #(#backcode)*
+                }
+            }
+        })
+        .collect();
 
     let key_inserts: Vec<_> = key_structs
         .iter()
@@ -505,46 +1074,42 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let key_insert_backrefs: Vec<_> = output
         .key_struct_maps
         .iter()
-        .enumerate()
-        .map(|(i, map)| {
-            let myname = &key_names[i];
-            let mut code = Vec::new();
+        .map(|map| {
             // The following keys_and_types is simply used to ensure we generate
             // a reproducible code.  This shouldn't be needed for correctness,
             // but when I had a bug it was a huge pain to have it randomly
             // disappearing.
             let mut keys_and_types = map.iter().collect::<Vec<_>>();
             keys_and_types.sort_by_key(|a| a.0);
-            for (k, v) in keys_and_types.into_iter() {
-                // or just map.iter()
-                match v {
-                    KeyType::Key(t) => {
-                        let field = quote::format_ident!("{}", t.to_string().to_snake_case());
-                        let rev = quote::format_ident!("{}_of", k.to_string().to_snake_case());
-                        code.push(quote::quote! {
-                            self.#field[self.#myname[idx].#k.0].#rev.insert(k);
-                        });
-                    }
-                    KeyType::OptionKey(t) => {
-                        let field = quote::format_ident!("{}", t.to_string().to_snake_case());
-                        let rev = quote::format_ident!("{}_of", k.to_string().to_snake_case());
-                        code.push(quote::quote! {
-                            if let Some(idxk) = self.#myname[idx].#k {
-                                self.#field[idxk.0].#rev.insert(k);
-                            }
-                        });
-                    }
-                    KeyType::KeySet(t) => {
-                        let field = quote::format_ident!("{}", t.to_string().to_snake_case());
-                        let rev = quote::format_ident!("{}_of", k.to_string().to_snake_case());
-                        code.push(quote::quote! {
-                            for idxk in self.#myname[idx].#k.iter() {
-                                self.#field[idxk.0].#rev.insert(k);
-                            }
-                        });
-                    }
-                }
+            let code = keys_and_types.into_iter().map(|(k, v)| {
+                backref_diff(
+                    k,
+                    &v.kind.key_to(),
+                    None,
+                    Some(v.keys(k, quote::quote! { datum })),
+                )
+            });
+            quote::quote! {
+                #(#code)*
             }
+        })
+        .collect();
+    let key_set_backrefs: Vec<_> = output
+        .key_struct_maps
+        .iter()
+        .enumerate()
+        .map(|(i, map)| {
+            let myname = &key_names[i];
+            let mut keys_and_types = map.iter().collect::<Vec<_>>();
+            keys_and_types.sort_by_key(|a| a.0);
+            let code = keys_and_types.into_iter().map(|(k, v)| {
+                backref_diff(
+                    k,
+                    &v.kind.key_to(),
+                    Some(v.keys(k, quote::quote! { old })),
+                    Some(v.keys(k, quote::quote! { self.#myname[k.0] })),
+                )
+            });
             quote::quote! {
                 #(#code)*
             }
@@ -562,56 +1127,956 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
             syn::parse_quote! {#i#g}
         })
         .collect();
-
-    // let save_enums = output.save_enums.iter();
-    let table_enums = output.pod_enums.iter();
-    // save_names.extend(
-    //     output.save_enums.iter().map(|x| x.ident.clone()));
-    let name = &input.name;
-    // let savename = quote::format_ident!("{}Save", name);
-    let output = quote::quote! {
-        trait Query: std::ops::Deref {
-            fn new(val: Self::Target) -> Self;
-        }
-        trait HasQuery {
-            type Query: Query<Target=Self>;
-        }
-        #(
-            #[repr(C)]
-            #[derive(Eq,PartialEq,Hash,Clone)]
-            #pod_structs
-            #[repr(C)]
-            #[derive(Eq,PartialEq,Hash,Clone)]
-            /// This is plain old data.
-            #pod_query_structs
-
-            impl std::ops::Deref for #pod_query_types {
-                type Target = #pod_types;
-                fn deref(&self) -> &Self::Target {
-                    &self.__data
-                }
-            }
-            impl Query for #pod_query_types {
-                fn new(value: Self::Target) -> Self {
-                    // First pad the value with zeroes, then transmute
-                    // to the query type.  This relies on zero bytes
-                    // being valid values for all extra fields in the
-                    // query struct.
-                    #pod_query_new
-                    // let x = (value,
-                    //          [0u8; std::mem::size_of::<Self>() - std::mem::size_of::<Self::Target>()]);
-                    // unsafe { std::mem::transmute(x) }
+    // A relationship of an enum may be held by several of its variants, or
+    // by none of them, so we need to match on the variant to find its keys.
+    let key_variant_keys: Vec<_> = key_structs
+        .iter()
+        .zip(output.key_struct_maps.iter())
+        .map(|(x, map)| {
+            let mytype = &x.ident;
+            let mut keys_and_types = map.iter().collect::<Vec<_>>();
+            keys_and_types.sort_by_key(|a| a.0);
+            let code = keys_and_types.into_iter().filter_map(|(k, v)| {
+                let variants = match &v.place {
+                    Place::Variants(variants) => variants,
+                    Place::Field(_) => return None,
+                };
+                let method = keys_fn(k);
+                let t = v.kind.key_to();
+                let keys = v.kind.keys(quote::quote! { (*x) });
+                let arms = variants.iter().map(|(variant, member)| {
+                    quote::quote! {
+                        #mytype::#variant { #member: x, .. } => #keys.collect(),
+                    }
+                });
+                Some(quote::quote! {
+                    fn #method(&self) -> Vec<Key<#t>> {
+                        #[allow(unreachable_patterns)]
+                        match self {
+                            #(#arms)*
+                            _ => Vec::new(),
+                        }
+                    }
+                })
+            });
+            if map.values().any(|v| v.member().is_none()) {
+                quote::quote! {
+                    impl #mytype {
+                        #(#code)*
+                    }
                 }
+            } else {
+                quote::quote! {}
             }
-            impl HasQuery for #pod_types {
-                type Query = #pod_query_types;
-            }
-        )*
-        #(
-            #[repr(C)]
-            #[derive(Clone)]
+        })
+        .collect();
+    let key_data_accessors: Vec<_> = key_structs
+        .iter()
+        .zip(output.key_struct_maps.iter())
+        .map(|(x, map)| {
+            let mytype = &x.ident;
+            let myname = quote::format_ident!("{}", mytype.to_string().to_snake_case());
+            let fields = match &x.body {
+                TableBody::Struct(fields) => fields.iter().collect(),
+                // The fields of an enum depend on its variant.
+                TableBody::Enum(_) => Vec::new(),
+            };
+            let keyed: Vec<_> = map.values().filter_map(|v| v.member()).collect();
+            let code = fields
+                .into_iter()
+                .enumerate()
+                .map(|(i, f)| match &f.ident {
+                    Some(ident) => (syn::Member::Named(ident.clone()), f),
+                    None => (
+                        syn::Member::Unnamed(syn::Index {
+                            index: i as u32,
+                            span: f.span(),
+                        }),
+                        f,
+                    ),
+                })
+                .filter(|(k, _)| !keyed.contains(&k))
+                .map(|(k, f)| {
+                    let ty = &f.ty;
+                    let field_name = match &k {
+                        syn::Member::Named(ident) => ident.to_string(),
+                        syn::Member::Unnamed(index) => index.index.to_string(),
+                    };
+                    let accessor = quote::format_ident!("with_{}_{}", myname, field_name);
+                    let try_accessor = quote::format_ident!("try_with_{}_{}", myname, field_name);
+                    quote::quote! {
+                        /// Change a field that holds no keys by running `f`
+                        /// on it, which needs no back references to be
+                        /// updated.
+                        pub fn #accessor<R>(&mut self, k: Key<#mytype>, f: impl FnOnce(&mut #ty) -> R) -> R {
+                            let step = self.__step_begin();
+                            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                f(&mut self.#myname[k.0].__data.#k)
+                            }));
+                            self.__step_close(step, r)
+                        }
+                        /// Like the method without `try_`, but fails rather than
+                        /// panicking if `k` does not refer to a row.
+                        pub fn #try_accessor<R>(&mut self, k: Key<#mytype>, f: impl FnOnce(&mut #ty) -> R) -> Result<R, polygraph::Error> {
+                            self.__check(AnyKey::#mytype(k))?;
+                            Ok(self.#accessor(k, f))
+                        }
+                    }
+                });
+            quote::quote! {
+                #(#code)*
+            }
+        })
+        .collect();
+    let key_modifies: Vec<_> = key_structs
+        .iter()
+        .map(|x| quote::format_ident!("modify_{}", x.ident.to_string().to_snake_case()))
+        .collect();
+    let key_modify_backrefs: Vec<_> = output
+        .key_struct_maps
+        .iter()
+        .enumerate()
+        .map(|(i, map)| {
+            let myname = &key_names[i];
+            let mut keys_and_types = map.iter().collect::<Vec<_>>();
+            keys_and_types.sort_by_key(|a| a.0);
+            let (before, after): (Vec<_>, Vec<_>) = keys_and_types
+                .into_iter()
+                .map(|(k, v)| {
+                    let old = quote::format_ident!("old_{}", k);
+                    let t = v.kind.key_to();
+                    let keys = v.keys(k, quote::quote! { self.#myname[k.0] });
+                    let before = quote::quote! {
+                        let #old: Vec<Key<#t>> = #keys.collect();
+                    };
+                    let after =
+                        backref_diff(k, &t, Some(quote::quote! { #old.into_iter() }), Some(keys));
+                    (before, after)
+                })
+                .unzip();
+            (quote::quote! { #(#before)* }, quote::quote! { #(#after)* })
+        })
+        .collect();
+    let (key_modify_before, key_modify_after): (Vec<_>, Vec<_>) =
+        key_modify_backrefs.into_iter().unzip();
+    let key_field_setters: Vec<_> = output
+        .key_struct_maps
+        .iter()
+        .enumerate()
+        .map(|(i, map)| {
+            let myname = &key_names[i];
+            let mytype = &key_structs[i].ident;
+            let mut keys_and_types = map.iter().collect::<Vec<_>>();
+            keys_and_types.sort_by_key(|a| a.0);
+            // A field of an enum is only there for some variants, so it
+            // cannot be set on its own.
+            keys_and_types.retain(|(_, v)| v.member().is_some());
+            let code = keys_and_types.into_iter().map(|(k, v)| {
+                let member = v.member();
+                let set = quote::format_ident!("set_{}_{}", myname, k);
+                let ty = v.kind.field_type();
+                let diff = backref_diff(
+                    k,
+                    &v.kind.key_to(),
+                    Some(v.kind.keys(quote::quote! { old })),
+                    Some(v.keys(k, quote::quote! { self.#myname[k.0] })),
+                );
+                let try_set = quote::format_ident!("try_set_{}_{}", myname, k);
+                let target = v.kind.key_to();
+                let value_keys = v.kind.keys(quote::quote! { value });
+                let setter = quote::quote! {
+                    /// Change just this field, updating any back references,
+                    /// and return its old value.
+                    pub fn #set(&mut self, k: Key<#mytype>, value: #ty) -> #ty {
+                        let step = self.__step_begin();
+                        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                            let old = std::mem::replace(&mut self.#myname[k.0].__data.#member, value);
+                            #diff
+                            old
+                        }));
+                        self.__step_close(step, r)
+                    }
+                    /// Like the method without `try_`, but fails rather than
+                    /// panicking if a key does not refer to a row.
+                    pub fn #try_set(&mut self, k: Key<#mytype>, value: #ty) -> Result<#ty, polygraph::Error> {
+                        self.__check(AnyKey::#mytype(k))?;
+                        for x in #value_keys {
+                            self.__check(AnyKey::#target(x))?;
+                        }
+                        Ok(self.#set(k, value))
+                    }
+                };
+                if let KeyType::KeySet(t) = &v.kind {
+                    let add = quote::format_ident!("add_{}_{}", myname, k);
+                    let remove = quote::format_ident!("remove_{}_{}", myname, k);
+                    let try_add = quote::format_ident!("try_add_{}_{}", myname, k);
+                    let try_remove = quote::format_ident!("try_remove_{}_{}", myname, k);
+                    let field = quote::format_ident!("{}", t.to_string().to_snake_case());
+                    let rev = quote::format_ident!("{}_of", k.to_string().to_snake_case());
+                    quote::quote! {
+                        #setter
+                        /// Add `value` to this set, updating its back
+                        /// references.  Returns `false` if it was already
+                        /// present.
+                        pub fn #add(&mut self, k: Key<#mytype>, value: Key<#t>) -> bool {
+                            let step = self.__step_begin();
+                            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                let added = self.#myname[k.0].__data.#member.insert(value);
+                                if added {
+                                    self.#field[value.0].#rev.insert(k);
+                                }
+                                added
+                            }));
+                            self.__step_close(step, r)
+                        }
+                        /// Remove `value` from this set, updating its back
+                        /// references.  Returns `false` if it was not present.
+                        pub fn #remove(&mut self, k: Key<#mytype>, value: Key<#t>) -> bool {
+                            let step = self.__step_begin();
+                            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                let removed = self.#myname[k.0].__data.#member.remove(&value);
+                                if removed {
+                                    self.#field[value.0].#rev.remove(&k);
+                                }
+                                removed
+                            }));
+                            self.__step_close(step, r)
+                        }
+                        /// Like the method without `try_`, but fails rather than
+                        /// panicking if a key does not refer to a row.
+                        pub fn #try_add(&mut self, k: Key<#mytype>, value: Key<#t>) -> Result<bool, polygraph::Error> {
+                            self.__check(AnyKey::#mytype(k))?;
+                            self.__check(AnyKey::#t(value))?;
+                            Ok(self.#add(k, value))
+                        }
+                        /// Like the method without `try_`, but fails rather than
+                        /// panicking if a key does not refer to a row.
+                        pub fn #try_remove(&mut self, k: Key<#mytype>, value: Key<#t>) -> Result<bool, polygraph::Error> {
+                            self.__check(AnyKey::#mytype(k))?;
+                            Ok(self.#remove(k, value))
+                        }
+                    }
+                } else if let KeyType::KeyVec(t) = &v.kind {
+                    let push = quote::format_ident!("push_{}_{}", myname, k);
+                    let insert = quote::format_ident!("insert_{}_{}_at", myname, k);
+                    let remove = quote::format_ident!("remove_{}_{}_at", myname, k);
+                    let try_push = quote::format_ident!("try_push_{}_{}", myname, k);
+                    let try_insert = quote::format_ident!("try_insert_{}_{}_at", myname, k);
+                    let try_remove = quote::format_ident!("try_remove_{}_{}_at", myname, k);
+                    let table = mytype.to_string();
+                    let field_name = k.to_string();
+                    let field = quote::format_ident!("{}", t.to_string().to_snake_case());
+                    let rev = quote::format_ident!("{}_of", k.to_string().to_snake_case());
+                    quote::quote! {
+                        #setter
+                        /// Add `value` to the end of this list, updating its
+                        /// back references.
+                        pub fn #push(&mut self, k: Key<#mytype>, value: Key<#t>) {
+                            let step = self.__step_begin();
+                            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                self.#myname[k.0].__data.#member.push(value);
+                                self.#field[value.0].#rev.insert(k);
+                            }));
+                            self.__step_close(step, r)
+                        }
+                        /// Insert `value` into this list at `index`, updating
+                        /// its back references.  Panics if `index` is past the
+                        /// end of the list.
+                        pub fn #insert(&mut self, k: Key<#mytype>, index: usize, value: Key<#t>) {
+                            let step = self.__step_begin();
+                            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                self.#myname[k.0].__data.#member.insert(index, value);
+                                self.#field[value.0].#rev.insert(k);
+                            }));
+                            self.__step_close(step, r)
+                        }
+                        /// Remove and return the key at `index` in this list,
+                        /// updating its back references.  Panics if `index` is
+                        /// out of bounds.
+                        pub fn #remove(&mut self, k: Key<#mytype>, index: usize) -> Key<#t> {
+                            let step = self.__step_begin();
+                            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                let removed = self.#myname[k.0].__data.#member.remove(index);
+                                if !self.#myname[k.0].#member.contains(&removed) {
+                                    self.#field[removed.0].#rev.remove(&k);
+                                }
+                                removed
+                            }));
+                            self.__step_close(step, r)
+                        }
+                        /// Like the method without `try_`, but fails rather than
+                        /// panicking if a key does not refer to a row.
+                        pub fn #try_push(&mut self, k: Key<#mytype>, value: Key<#t>) -> Result<(), polygraph::Error> {
+                            self.__check(AnyKey::#mytype(k))?;
+                            self.__check(AnyKey::#t(value))?;
+                            self.#push(k, value);
+                            Ok(())
+                        }
+                        /// Like the method without `try_`, but fails rather than
+                        /// panicking if a key does not refer to a row or
+                        /// `index` is past the end of the list.
+                        pub fn #try_insert(&mut self, k: Key<#mytype>, index: usize, value: Key<#t>) -> Result<(), polygraph::Error> {
+                            self.__check(AnyKey::#mytype(k))?;
+                            self.__check(AnyKey::#t(value))?;
+                            let len = self.#myname[k.0].#member.len();
+                            if index > len {
+                                return Err(polygraph::Error::OutOfBounds { table: #table, field: #field_name, index, len });
+                            }
+                            self.#insert(k, index, value);
+                            Ok(())
+                        }
+                        /// Like the method without `try_`, but fails rather than
+                        /// panicking if a key does not refer to a row or
+                        /// `index` is out of bounds.
+                        pub fn #try_remove(&mut self, k: Key<#mytype>, index: usize) -> Result<Key<#t>, polygraph::Error> {
+                            self.__check(AnyKey::#mytype(k))?;
+                            let len = self.#myname[k.0].#member.len();
+                            if index >= len {
+                                return Err(polygraph::Error::OutOfBounds { table: #table, field: #field_name, index, len });
+                            }
+                            Ok(self.#remove(k, index))
+                        }
+                    }
+                } else if let KeyType::KeyMap(t, p) = &v.kind {
+                    let add = quote::format_ident!("add_{}_{}", myname, k);
+                    let remove = quote::format_ident!("remove_{}_{}", myname, k);
+                    let payload = quote::format_ident!("with_{}_{}", myname, k);
+                    let try_add = quote::format_ident!("try_add_{}_{}", myname, k);
+                    let try_remove = quote::format_ident!("try_remove_{}_{}", myname, k);
+                    let try_payload = quote::format_ident!("try_with_{}_{}", myname, k);
+                    let field = quote::format_ident!("{}", t.to_string().to_snake_case());
+                    let rev = quote::format_ident!("{}_of", k.to_string().to_snake_case());
+                    quote::quote! {
+                        #setter
+                        /// Add `value` to this map with the given payload,
+                        /// updating its back references.  Returns the old
+                        /// payload if it was already present.
+                        pub fn #add(&mut self, k: Key<#mytype>, value: Key<#t>, payload: #p) -> Option<#p> {
+                            let step = self.__step_begin();
+                            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                let old = self.#myname[k.0].__data.#member.insert(value, payload);
+                                self.#field[value.0].#rev.insert(k);
+                                old
+                            }));
+                            self.__step_close(step, r)
+                        }
+                        /// Remove `value` from this map, updating its back
+                        /// references.  Returns its payload if it was present.
+                        pub fn #remove(&mut self, k: Key<#mytype>, value: Key<#t>) -> Option<#p> {
+                            let step = self.__step_begin();
+                            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                let removed = self.#myname[k.0].__data.#member.remove(&value);
+                                if removed.is_some() {
+                                    self.#field[value.0].#rev.remove(&k);
+                                }
+                                removed
+                            }));
+                            self.__step_close(step, r)
+                        }
+                        /// Change the payload of `value` in this map by running
+                        /// `f` on it, which needs no back references to be
+                        /// updated.  Returns `None` without running `f` if
+                        /// `value` is not in the map.
+                        pub fn #payload<R>(&mut self, k: Key<#mytype>, value: Key<#t>, f: impl FnOnce(&mut #p) -> R) -> Option<R> {
+                            if !self.#myname[k.0].#member.contains_key(&value) {
+                                return None;
+                            }
+                            let step = self.__step_begin();
+                            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                self.#myname[k.0].__data.#member.get_mut(&value).map(f)
+                            }));
+                            self.__step_close(step, r)
+                        }
+                        /// Like the method without `try_`, but fails rather than
+                        /// panicking if a key does not refer to a row.
+                        pub fn #try_add(&mut self, k: Key<#mytype>, value: Key<#t>, payload: #p) -> Result<Option<#p>, polygraph::Error> {
+                            self.__check(AnyKey::#mytype(k))?;
+                            self.__check(AnyKey::#t(value))?;
+                            Ok(self.#add(k, value, payload))
+                        }
+                        /// Like the method without `try_`, but fails rather than
+                        /// panicking if a key does not refer to a row.
+                        pub fn #try_remove(&mut self, k: Key<#mytype>, value: Key<#t>) -> Result<Option<#p>, polygraph::Error> {
+                            self.__check(AnyKey::#mytype(k))?;
+                            Ok(self.#remove(k, value))
+                        }
+                        /// Like the method without `try_`, but fails rather than
+                        /// panicking if `k` does not refer to a row.
+                        pub fn #try_payload<R>(&mut self, k: Key<#mytype>, value: Key<#t>, f: impl FnOnce(&mut #p) -> R) -> Result<Option<R>, polygraph::Error> {
+                            self.__check(AnyKey::#mytype(k))?;
+                            Ok(self.#payload(k, value, f))
+                        }
+                    }
+                } else {
+                    setter
+                }
+            });
+            quote::quote! {
+                #(#code)*
+            }
+        })
+        .collect();
+
+    // Each relationship can be observed, along with each table.
+    let mut edge_names = Vec::new();
+    let mut edge_observes = Vec::new();
+    let mut edge_froms = Vec::new();
+    let mut edge_tos = Vec::new();
+    let key_edge_notifies: Vec<_> = output
+        .key_struct_maps
+        .iter()
+        .enumerate()
+        .map(|(i, map)| {
+            let myname = &key_names[i];
+            let mytype = &key_structs[i].ident;
+            let mut keys_and_types = map.iter().collect::<Vec<_>>();
+            keys_and_types.sort_by_key(|a| a.0);
+            let code = keys_and_types
+                .into_iter()
+                .map(|(k, v)| {
+                    let edge = quote::format_ident!("{}_{}", myname, k);
+                    let t = v.kind.key_to();
+                    let keys = v.keys(k, quote::quote! { row });
+                    edge_names.push(edge.clone());
+                    edge_observes.push(quote::format_ident!("observe_{}", edge));
+                    edge_froms.push(mytype.clone());
+                    edge_tos.push(t.clone());
+                    quote::quote! {
+                        if !self.__observers.#edge.is_empty() {
+                            let before: Vec<Key<#t>> =
+                                old.iter().flat_map(|&(_, row)| #keys).collect();
+                            let after: Vec<Key<#t>> =
+                                new.iter().flat_map(|&(_, row)| #keys).collect();
+                            let same = old_key == new_key;
+                            if let Some(k) = old_key {
+                                for &to in before.iter().filter(|x| !same || !after.contains(x)) {
+                                    self.__observers.#edge.notify(&polygraph::EdgeChange::Removed(k, to));
+                                }
+                            }
+                            if let Some(k) = new_key {
+                                for &to in after.iter().filter(|x| !same || !before.contains(x)) {
+                                    self.__observers.#edge.notify(&polygraph::EdgeChange::Added(k, to));
+                                }
+                            }
+                        }
+                    }
+                })
+                .collect::<Vec<_>>();
+            let edges = &edge_names[edge_names.len() - code.len()..];
+            let observed = quote::quote! { #( || !self.__observers.#edges.is_empty() )* };
+            (quote::quote! { #(#code)* }, observed)
+        })
+        .collect();
+
+    // Everything below is about removing rows, which requires that we
+    // know how to find all the rows that refer to a given row, and what
+    // each reference wants done when its target goes away.
+    let all_types: Vec<_> = pod_structs
+        .iter()
+        .chain(key_structs.iter())
+        .map(|x| x.ident.clone())
+        .collect();
+    let all_names: Vec<_> = all_types
+        .iter()
+        .map(|x| quote::format_ident!("{}", x.to_string().to_snake_case()))
+        .collect();
+    let no_refs = Vec::new();
+    let incoming: Vec<_> = all_types
+        .iter()
+        .map(|t| reverse_references.get(t).unwrap_or(&no_refs))
+        .collect();
+    let cascade_arms: Vec<_> = incoming
+        .iter()
+        .zip(all_names.iter())
+        .map(|(refs, myname)| {
+            let code = refs
+                .iter()
+                .filter(|(_, _, f)| f.on_delete == OnDelete::Cascade)
+                .map(|(src, field, _)| {
+                    let rev = quote::format_ident!("{}_of", field.to_string().to_snake_case());
+                    quote::quote! {
+                        for r in self.#myname[k.0].#rev.iter() {
+                            todo.push(AnyKey::#src(r));
+                        }
+                    }
+                });
+            quote::quote! { #(#code)* }
+        })
+        .collect();
+    let restrict_arms: Vec<_> = incoming
+        .iter()
+        .zip(all_names.iter())
+        .map(|(refs, myname)| {
+            let code = refs
+                .iter()
+                .filter(|(_, _, f)| f.on_delete == OnDelete::Restrict)
+                .map(|(src, field, _)| {
+                    let rev = quote::format_ident!("{}_of", field.to_string().to_snake_case());
+                    let table = src.to_string();
+                    let field = field.to_string();
+                    quote::quote! {
+                        for r in self.#myname[k.0].#rev.iter() {
+                            if !doomed.contains(&AnyKey::#src(r)) {
+                                return Err(polygraph::Error::Restricted {
+                                    table: #table,
+                                    field: #field,
+                                });
+                            }
+                        }
+                    }
+                });
+            quote::quote! { #(#code)* }
+        })
+        .collect();
+    let mut unlink_arms: Vec<_> = incoming
+        .iter()
+        .zip(all_names.iter())
+        .map(|(refs, myname)| {
+            let code = refs
+                .iter()
+                .filter(|(_, _, f)| f.on_delete == OnDelete::SetNull)
+                .map(|(src, field, f)| {
+                    let rev = quote::format_ident!("{}_of", field.to_string().to_snake_case());
+                    let srcname = quote::format_ident!("{}", src.to_string().to_snake_case());
+                    let clear = match f.kind {
+                        KeyType::KeySet(_) | KeyType::KeyMap(..) => {
+                            quote::quote! { x.remove(&k); }
+                        }
+                        KeyType::KeyVec(_) => quote::quote! { x.retain(|&y| y != k); },
+                        _ => quote::quote! { *x = None; },
+                    };
+                    let clear =
+                        f.with_field_mut(src, quote::quote! { self.#srcname[r.0].__data }, clear);
+                    quote::quote! {
+                        for r in self.#myname[k.0].#rev.iter().collect::<Vec<_>>() {
+                            #clear
+                        }
+                    }
+                });
+            quote::quote! { #(#code)* }
+        })
+        .collect();
+    // Rows we remove must also be removed from the reverse sets of
+    // whatever they point to.
+    for (i, map) in output.key_struct_maps.iter().enumerate() {
+        let myname = &key_names[i];
+        let mut keys_and_types = map.iter().collect::<Vec<_>>();
+        keys_and_types.sort_by_key(|a| a.0);
+        let code = keys_and_types.into_iter().map(|(k, v)| {
+            backref_diff(
+                k,
+                &v.kind.key_to(),
+                Some(v.keys(k, quote::quote! { self.#myname[k.0] })),
+                None,
+            )
+        });
+        unlink_arms[pod_structs.len() + i].extend(code);
+    }
+    let free_rows: Vec<_> = all_names
+        .iter()
+        .enumerate()
+        .map(|(i, myname)| {
+            if i < pod_structs.len() {
+                let hash = quote::format_ident!("hash_{}", myname);
+                let rehash = quote::format_ident!("__rehash_{}", myname);
+                quote::quote! {
+                    {
+                        // Only a table with duplicate rows has fewer
+                        // values in its lookup than rows.
+                        let duplicated = self.#hash.len() < self.#myname.len();
+                        let row = self.#myname.remove(k.0).expect("no row with this key");
+                        if self.#hash.get(&row.__data) == Some(&k.0) {
+                            self.#hash.remove(&row.__data);
+                            if duplicated {
+                                self.#rehash(row.__data.clone());
+                            }
+                        }
+                        row
+                    }
+                }
+            } else {
+                quote::quote! {
+                    self.#myname.remove(k.0).expect("no row with this key")
+                }
+            }
+        })
+        .collect();
+    let all_query_types: Vec<_> = pod_query_types
+        .iter()
+        .chain(key_query_types.iter())
+        .collect();
+    let all_gets: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("get_{}", x))
+        .collect();
+    let all_removes: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("remove_{}", x))
+        .collect();
+
+    // Pointing every reference at one row to another instead is how we
+    // merge rows.
+    let repoints: Vec<_> = incoming
+        .iter()
+        .zip(all_names.iter())
+        .map(|(refs, myname)| {
+            let code = refs.iter().map(|(src, field, f)| {
+                let rev = quote::format_ident!("{}_of", field.to_string().to_snake_case());
+                let srcname = quote::format_ident!("{}", src.to_string().to_snake_case());
+                let rewrite = match f.kind {
+                    KeyType::Key(_) => quote::quote! { *x = to; },
+                    KeyType::OptionKey(_) => quote::quote! { *x = Some(to); },
+                    KeyType::KeySet(_) => quote::quote! {
+                        x.remove(&from);
+                        x.insert(to);
+                    },
+                    // Where both rows were already in the map, we keep the
+                    // payload of the row being kept.
+                    KeyType::KeyMap(..) => quote::quote! {
+                        if let Some(payload) = x.remove(&from) {
+                            x.entry(to).or_insert(payload);
+                        }
+                    },
+                    KeyType::KeyVec(_) => quote::quote! {
+                        for y in x.iter_mut().filter(|y| **y == from) {
+                            *y = to;
+                        }
+                    },
+                };
+                let rewrite =
+                    f.with_field_mut(src, quote::quote! { self.#srcname[r.0].__data }, rewrite);
+                quote::quote! {
+                    let refs = std::mem::take(&mut self.#myname[from.0].#rev);
+                    for r in refs.iter() {
+                        #rewrite
+                        self.#myname[to.0].#rev.insert(r);
+                    }
+                }
+            });
+            quote::quote! { #(#code)* }
+        })
+        .collect();
+    let pod_sets: Vec<_> = pod_names
+        .iter()
+        .map(|x| quote::format_ident!("set_{}", x))
+        .collect();
+    let pod_set_or_merges: Vec<_> = pod_names
+        .iter()
+        .map(|x| quote::format_ident!("set_or_merge_{}", x))
+        .collect();
+    let all_repoint_fns: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("__repoint_{}", x))
+        .collect();
+    let pod_repoint_fns = &all_repoint_fns[..pod_structs.len()];
+    let pod_rehashes: Vec<_> = pod_names
+        .iter()
+        .map(|x| quote::format_ident!("__rehash_{}", x))
+        .collect();
+    let all_merges: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("merge_{}", x))
+        .collect();
+    let pod_removes = &all_removes[..pod_structs.len()];
+    let pod_type_names: Vec<_> = pod_structs.iter().map(|x| x.ident.to_string()).collect();
+    let all_type_names: Vec<_> = all_types.iter().map(|x| x.to_string()).collect();
+    let all_try_merges: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("try_merge_{}", x))
+        .collect();
+    let pod_try_set_or_merges: Vec<_> = pod_structs
+        .iter()
+        .map(|x| quote::format_ident!("try_set_or_merge_{}", x.ident.to_string().to_snake_case()))
+        .collect();
+    let key_try_inserts: Vec<_> = key_names
+        .iter()
+        .map(|x| quote::format_ident!("try_insert_{}", x))
+        .collect();
+    let key_try_sets: Vec<_> = key_names
+        .iter()
+        .map(|x| quote::format_ident!("try_set_{}", x))
+        .collect();
+    let key_try_modifies: Vec<_> = key_names
+        .iter()
+        .map(|x| quote::format_ident!("try_modify_{}", x))
+        .collect();
+    // Checks that every key held in `datum` refers to a row.
+    let key_datum_checks: Vec<_> = output
+        .key_struct_maps
+        .iter()
+        .map(|map| {
+            let mut keys_and_types = map.iter().collect::<Vec<_>>();
+            keys_and_types.sort_by_key(|a| a.0);
+            let code = keys_and_types.into_iter().map(|(k, v)| {
+                let t = v.kind.key_to();
+                let keys = v.keys(k, quote::quote! { datum });
+                quote::quote! {
+                    for x in #keys {
+                        self.__check(AnyKey::#t(x))?;
+                    }
+                }
+            });
+            quote::quote! { #(#code)* }
+        })
+        .collect();
+    let key_type_idents: Vec<_> = key_structs.iter().map(|x| x.ident.clone()).collect();
+    let all_lives: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("live_{}", x))
+        .collect();
+    // A live query may look at its row, the rows that row refers to, and
+    // the rows that refer to it, so a change to any of those means the
+    // row must be checked again.  The reverse sets tell us which rows
+    // refer to a changed row.
+    let live_updates: Vec<_> = all_names
+        .iter()
+        .enumerate()
+        .map(|(i, myname)| {
+            let mytype = &all_types[i];
+            let mut sources = Vec::new();
+            if let Some(map) = i
+                .checked_sub(pod_structs.len())
+                .map(|i| &output.key_struct_maps[i])
+            {
+                let mut keys_and_types = map.iter().collect::<Vec<_>>();
+                keys_and_types.sort_by_key(|a| a.0);
+                for (k, v) in keys_and_types {
+                    let target =
+                        quote::format_ident!("{}", v.kind.key_to().to_string().to_snake_case());
+                    let rev = quote::format_ident!("{}_of", k.to_string().to_snake_case());
+                    sources.push(quote::quote! {
+                        for (old, new) in self.#target.diff(&changes.#target) {
+                            for &(_, row) in old.iter().chain(new.iter()) {
+                                keys.extend(row.#rev.iter());
+                            }
+                        }
+                    });
+                }
+            }
+            for (src, field, f) in incoming[i].iter() {
+                let srcname = quote::format_ident!("{}", src.to_string().to_snake_case());
+                let refs = f.keys(field, quote::quote! { row });
+                sources.push(quote::quote! {
+                    for (old, new) in self.#srcname.diff(&changes.#srcname) {
+                        for &(_, row) in old.iter().chain(new.iter()) {
+                            keys.extend(#refs);
+                        }
+                    }
+                });
+            }
+            quote::quote! {
+                if !self.__live.#myname.is_empty() {
+                    let mut keys: Vec<Key<#mytype>> = Vec::new();
+                    for (old, new) in self.#myname.diff(&changes.#myname) {
+                        keys.extend(
+                            old.iter()
+                                .chain(new.iter())
+                                .map(|&(k, _)| Key(k, std::marker::PhantomData)),
+                        );
+                    }
+                    #(#sources)*
+                    let keys: KeySet<#mytype> = keys.into_iter().collect();
+                    let (changed, removed): (Vec<_>, Vec<_>) =
+                        keys.iter().partition(|k| self.#myname.get(k.0).is_some());
+                    let mut live = std::mem::take(&mut self.__live.#myname);
+                    live.update(self, &changed, &removed);
+                    self.__live.#myname = live;
+                }
+            }
+        })
+        .collect();
+    // Navigation from a `Ref` follows each relationship in either
+    // direction.
+    let mut ref_methods: Vec<Vec<proc_macro2::TokenStream>> = vec![Vec::new(); all_types.len()];
+    for (i, map) in output.key_struct_maps.iter().enumerate() {
+        let mut keys_and_types = map.iter().collect::<Vec<_>>();
+        keys_and_types.sort_by_key(|a| a.0);
+        for (k, v) in keys_and_types {
+            let t = v.kind.key_to();
+            let member = v.member();
+            let method = match &v.kind {
+                // Which variant a row of an enum is decides whether it
+                // holds this relationship.
+                KeyType::Key(_) | KeyType::OptionKey(_) if v.member().is_none() => {
+                    let keys = v.keys(k, quote::quote! { self.db[self.key] });
+                    quote::quote! {
+                        /// The row this field refers to, if this row has it.
+                        pub fn #k(self) -> Option<Ref<'a, #t>> {
+                            #keys.next().map(|k| k.r(self.db))
+                        }
+                    }
+                }
+                KeyType::KeySet(_) | KeyType::KeyVec(_) | KeyType::KeyMap(..)
+                    if v.member().is_none() =>
+                {
+                    let keys = v.keys(k, quote::quote! { self.db[self.key] });
+                    quote::quote! {
+                        /// The rows this field refers to, if this row has it.
+                        pub fn #k(self) -> impl Iterator<Item = Ref<'a, #t>> {
+                            let db = self.db;
+                            #keys.map(move |k| k.r(db))
+                        }
+                    }
+                }
+                KeyType::Key(_) => quote::quote! {
+                    /// The row this field refers to.
+                    pub fn #k(self) -> Ref<'a, #t> {
+                        self.db[self.key].#member.r(self.db)
+                    }
+                },
+                KeyType::OptionKey(_) => quote::quote! {
+                    /// The row this field refers to, if any.
+                    pub fn #k(self) -> Option<Ref<'a, #t>> {
+                        self.db[self.key].#member.map(|k| k.r(self.db))
+                    }
+                },
+                KeyType::KeyMap(_, p) => quote::quote! {
+                    /// The rows this field refers to, each with its payload.
+                    pub fn #k(self) -> impl Iterator<Item = (Ref<'a, #t>, &'a #p)> {
+                        let db = self.db;
+                        db[self.key].#member.iter().map(move |(k, p)| (k.r(db), p))
+                    }
+                },
+                KeyType::KeyVec(_) => quote::quote! {
+                    /// The rows this field refers to, in order.
+                    pub fn #k(self) -> impl Iterator<Item = Ref<'a, #t>> {
+                        let db = self.db;
+                        db[self.key].#member.iter().map(move |k| k.r(db))
+                    }
+                },
+                KeyType::KeySet(_) => quote::quote! {
+                    /// The rows this field refers to.
+                    pub fn #k(self) -> RefSet<'a, #t> {
+                        RefSet {
+                            db: self.db,
+                            set: &self.db[self.key].#member,
+                        }
+                    }
+                },
+            };
+            ref_methods[pod_structs.len() + i].push(method);
+        }
+    }
+    for (i, refs) in incoming.iter().enumerate() {
+        for (src, field, f) in refs.iter() {
+            let rev = quote::format_ident!("{}_of", field.to_string().to_snake_case());
+            ref_methods[i].push(quote::quote! {
+                /// The rows that refer to this one through this field.
+                pub fn #rev(self) -> RefSet<'a, #src> {
+                    RefSet {
+                        db: self.db,
+                        set: &self.db[self.key].#rev,
+                    }
+                }
+            });
+            if let (KeyType::KeyMap(_, p), Some(member)) = (&f.kind, f.member()) {
+                let payloads = quote::format_ident!("{}_payloads", rev);
+                ref_methods[i].push(quote::quote! {
+                    /// The rows that refer to this one through this field,
+                    /// each with the payload it gives this row.
+                    pub fn #payloads(self) -> impl Iterator<Item = (Ref<'a, #src>, &'a #p)> {
+                        let db = self.db;
+                        let key = self.key;
+                        db[key].#rev.iter().map(move |r| (r.r(db), &db[r].#member[&key]))
+                    }
+                });
+            }
+        }
+    }
+    let ref_methods: Vec<_> = ref_methods
+        .into_iter()
+        .map(|methods| quote::quote! { #(#methods)* })
+        .collect();
+    let all_plurals: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("{}", plural(&x.to_string())))
+        .collect();
+    let all_key_iters: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("{}_keys", x))
+        .collect();
+    let all_lens: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("len_{}", x))
+        .collect();
+    let all_is_empties: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("is_empty_{}", x))
+        .collect();
+    let all_observes: Vec<_> = all_names
+        .iter()
+        .map(|x| quote::format_ident!("observe_{}", x))
+        .collect();
+    let table_notifies: Vec<_> = all_names
+        .iter()
+        .enumerate()
+        .map(|(i, myname)| {
+            let (edges, observed) = match i.checked_sub(pod_structs.len()) {
+                Some(i) => key_edge_notifies[i].clone(),
+                None => (quote::quote! {}, quote::quote! {}),
+            };
+            quote::quote! {
+                if !self.__observers.#myname.is_empty() #observed {
+                    for (old, new) in self.#myname.diff(&changes.#myname) {
+                        let old_key = old.map(|(k, _)| Key(k, std::marker::PhantomData));
+                        let new_key = new.map(|(k, _)| Key(k, std::marker::PhantomData));
+                        self.__observers.#myname.notify_row(old_key, new_key);
+                        #edges
+                    }
+                }
+            }
+        })
+        .collect();
+
+    // let save_enums = output.save_enums.iter();
+    // save_names.extend(
+    //     output.save_enums.iter().map(|x| x.ident.clone()));
+    let name = &input.name;
+    // let savename = quote::format_ident!("{}Save", name);
+    let name_attrs = &input.attrs;
+    // A branded database starts the generations of its slots from a
+    // random number, so that a key from any other database is very
+    // unlikely to find a row in it.
+    let brand = if input.branded {
+        quote::quote! { polygraph::new_brand() }
+    } else {
+        quote::quote! { 0 }
+    };
+    let changes = quote::format_ident!("{}Changes", name);
+    let observers = quote::format_ident!("{}Observers", name);
+    let lives = quote::format_ident!("{}LiveQueries", name);
+    let output = quote::quote! {
+        trait Query: std::ops::Deref {
+            fn new(val: Self::Target) -> Self;
+        }
+        trait HasQuery {
+            type Query: Query<Target=Self>;
+        }
+        #(
+            #[derive(Eq,PartialEq,Hash,Clone)]
+            #pod_structs
+            #[derive(Eq,PartialEq,Hash,Clone)]
+            /// This is plain old data.
+            #pod_query_structs
+
+            impl std::ops::Deref for #pod_query_types {
+                type Target = #pod_types;
+                fn deref(&self) -> &Self::Target {
+                    &self.__data
+                }
+            }
+            impl Query for #pod_query_types {
+                fn new(value: Self::Target) -> Self {
+                    #pod_query_new
+                }
+            }
+            impl HasQuery for #pod_types {
+                type Query = #pod_query_types;
+            }
+        )*
+        #(
+            #[derive(Clone)]
             #key_structs
-            #[repr(C)]
             #[derive(Clone)]
             /// This table has keys to other tables
             #key_query_structs
@@ -619,94 +2084,460 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
             impl std::ops::Deref for #key_query_types {
                 type Target = #key_types;
                 fn deref(&self) -> &Self::Target {
-                    unsafe { &*(self as *const Self as *const Self::Target) }
+                    &self.__data
                 }
             }
             impl Query for #key_query_types {
                 fn new(value: Self::Target) -> Self {
-                    // First pad the value with zeroes, then transmute
-                    // to the query type.  This relies on zero bytes
-                    // being valid values for all extra fields in the
-                    // query struct.
-                    // unimplemented!()
-                    let x = (value,
-                             [0u8; std::mem::size_of::<Self>() - std::mem::size_of::<Self::Target>()]);
-                    unsafe { std::mem::transmute(x) }
+                    #key_query_new
                 }
             }
             impl HasQuery for #key_types {
                 type Query = #key_query_types;
             }
-        )*
-        #(
-            #[derive(Eq,PartialEq,Hash,Clone)]
-            #table_enums
+            #key_variant_keys
         )*
 
+        #( #name_attrs )*
         pub struct #name {
             #(
-                pub #pod_names: Vec<#pod_query_types>,
+                #pod_names: polygraph::Table<#pod_query_types>,
             )*
             #(
-                pub #key_names: Vec<#key_query_types>,
+                #key_names: polygraph::Table<#key_query_types>,
             )*
             #(
-                pub #pod_lookup_hashes: std::collections::HashMap<#pod_types, usize>,
+                #pod_lookup_hashes: polygraph::Lookup<#pod_types>,
             )*
+            __history: polygraph::History<#changes>,
+            __observers: #observers,
+            __live: #lives,
         }
         impl #name {
             /// Create an empty #name database.
             pub fn new() -> Self {
+                let brand = #brand;
                 #name {
-                    #( #pod_names: Vec::new(), )*
-                    #( #key_names: Vec::new(), )*
+                    #( #pod_names: polygraph::Table::branded(brand), )*
+                    #( #key_names: polygraph::Table::branded(brand), )*
                     #(
-                        #pod_lookup_hashes: std::collections::HashMap::new(),
+                        #pod_lookup_hashes: polygraph::Lookup::new(),
                     )*
+                    __history: polygraph::History::new(),
+                    __observers: #observers::default(),
+                    __live: #lives::default(),
+                }
+            }
+
+            #(
+                /// Iterate over the rows of this table along with their
+                /// keys.
+                pub fn #all_plurals(
+                    &self,
+                ) -> impl Iterator<Item = (Key<#all_types>, &#all_query_types)> + '_ {
+                    self.#all_names
+                        .iter()
+                        .map(|(k, row)| (Key(k, std::marker::PhantomData), row))
+                }
+                /// Iterate over the keys of the rows of this table.
+                pub fn #all_key_iters(&self) -> impl Iterator<Item = Key<#all_types>> + '_ {
+                    self.#all_names
+                        .iter()
+                        .map(|(k, _)| Key(k, std::marker::PhantomData))
+                }
+                /// The number of rows in this table.
+                pub fn #all_lens(&self) -> usize {
+                    self.#all_names.len()
+                }
+                /// Whether this table has no rows.
+                pub fn #all_is_empties(&self) -> bool {
+                    self.#all_names.is_empty()
+                }
+            )*
+
+            /// An immutable view of the database as it is now, which later
+            /// changes to the database will not affect.  This takes
+            /// constant time, since the snapshot shares storage with the
+            /// database until either is modified.
+            pub fn snapshot(&self) -> polygraph::Snapshot<Self> {
+                polygraph::Snapshot::new(#name {
+                    #( #all_names: self.#all_names.snapshot(), )*
+                    #( #pod_lookup_hashes: self.#pod_lookup_hashes.snapshot(), )*
+                    __history: polygraph::History::new(),
+                    __observers: #observers::default(),
+                    __live: #lives::default(),
+                })
+            }
+
+            /// Run `f` as a single transaction.  If it returns an error or
+            /// panics, every change it made is undone before the error is
+            /// returned (or the panic resumed).  Transactions may be nested.
+            pub fn transaction<R, E>(&mut self, f: impl FnOnce(&mut Self) -> Result<R, E>) -> Result<R, E> {
+                // A transaction is always recorded, so that the changes of
+                // everything inside it are folded into its journal, even if
+                // history is enabled or an observer added partway through.
+                let step = self.__step_enter(true);
+                self.__begin();
+                match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(self))) {
+                    Ok(Ok(r)) => {
+                        self.__commit();
+                        self.__step_end(step);
+                        Ok(r)
+                    }
+                    Ok(Err(e)) => {
+                        self.__rollback(step);
+                        Err(e)
+                    }
+                    Err(panic) => {
+                        self.__rollback(step);
+                        std::panic::resume_unwind(panic)
+                    }
+                }
+            }
+            fn __rollback(&mut self, step: bool) {
+                let changes = self.__end();
+                self.__revert(changes);
+                if step {
+                    // Nothing has changed since the step began, so there is
+                    // nothing to record.
+                    self.__end();
+                    self.__history.exit(step);
+                }
+            }
+
+            /// Start recording every change, so that it can be undone.
+            /// Each call that modifies the database (including a whole
+            /// `transaction`) is one step of undo.
+            pub fn enable_history(&mut self) {
+                self.__history.enable();
+            }
+            /// Undo the most recent step, returning `false` if there was
+            /// nothing to undo.  Keys to rows inserted by the step will not
+            /// find any row until it is redone.
+            pub fn undo(&mut self) -> bool {
+                assert!(!self.__history.is_recording(), "cannot undo during a transaction");
+                match self.__history.pop_undo() {
+                    Some(changes) => {
+                        let redo = self.__revert(changes);
+                        self.__notify(&redo);
+                        self.__update_live(&redo);
+                        self.__history.push_redo(redo);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            /// Redo the most recently undone step, returning `false` if
+            /// there was nothing to redo.
+            pub fn redo(&mut self) -> bool {
+                assert!(!self.__history.is_recording(), "cannot redo during a transaction");
+                match self.__history.pop_redo() {
+                    Some(changes) => {
+                        let undo = self.__revert(changes);
+                        self.__notify(&undo);
+                        self.__update_live(&undo);
+                        self.__history.push_undo(undo);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            /// Name the current state, so that `undo_to` can return to it.
+            pub fn checkpoint(&mut self, name: &str) {
+                self.__history.checkpoint(name);
+            }
+            /// Undo every step since the most recent checkpoint with this
+            /// name, returning `false` (and undoing nothing) if there is no
+            /// such checkpoint to go back to.
+            pub fn undo_to(&mut self, name: &str) -> bool {
+                match self.__history.steps_since(name) {
+                    Some(n) => {
+                        for _ in 0..n {
+                            self.undo();
+                        }
+                        true
+                    }
+                    None => false,
+                }
+            }
+
+            #(
+                /// Call `f` with every change to the rows of this table,
+                /// once each modification of the database is complete.
+                pub fn #all_observes(
+                    &mut self,
+                    f: impl FnMut(&polygraph::Change<Key<#all_types>>) + Send + 'static,
+                ) -> polygraph::ObserverId {
+                    self.__observers.#all_names.add(f)
+                }
+            )*
+            #(
+                /// Call `f` with every edge this relationship gains or
+                /// loses, once each modification of the database is
+                /// complete.
+                pub fn #edge_observes(
+                    &mut self,
+                    f: impl FnMut(&polygraph::EdgeChange<Key<#edge_froms>, Key<#edge_tos>>) + Send + 'static,
+                ) -> polygraph::ObserverId {
+                    self.__observers.#edge_names.add(f)
+                }
+            )*
+            /// Stop calling an observer, returning `false` if it had
+            /// already been removed.
+            pub fn unobserve(&mut self, id: polygraph::ObserverId) -> bool {
+                false #( || self.__observers.#all_names.remove(id) )* #( || self.__observers.#edge_names.remove(id) )*
+            }
+
+            #(
+                /// Keep the set of rows of this table for which `f` is true
+                /// up to date as the database changes.  The result is found
+                /// by indexing the database with the returned handle.
+                ///
+                /// `f` may look at the row, the rows it refers to, and the
+                /// rows that refer to it.  Changes to rows any further away
+                /// will not cause the row to be checked again.
+                pub fn #all_lives(
+                    &mut self,
+                    f: impl Fn(&Self, Key<#all_types>) -> bool + Send + Sync + 'static,
+                ) -> polygraph::Live<Key<#all_types>> {
+                    let rows: Vec<_> = self.#all_key_iters().collect();
+                    let mut live = std::mem::take(&mut self.__live.#all_names);
+                    let handle = live.add(self, rows.into_iter(), f);
+                    self.__live.#all_names = live;
+                    handle
+                }
+            )*
+            /// Stop keeping a live query up to date, returning `false` if it
+            /// had already been dropped.
+            pub fn drop_live<K>(&mut self, live: polygraph::Live<K>) -> bool {
+                false #( || self.__live.#all_names.remove(live) )*
+            }
+
+            fn __update_live(&mut self, changes: &#changes) {
+                #( #live_updates )*
+            }
+
+            fn __notify(&mut self, changes: &#changes) {
+                #( #table_notifies )*
+            }
+
+            fn __step_begin(&mut self) -> bool {
+                let watched = !self.__observers.is_empty() || !self.__live.is_empty();
+                self.__step_enter(watched)
+            }
+            fn __step_enter(&mut self, record: bool) -> bool {
+                let step = self.__history.enter(record);
+                if step {
+                    self.__begin();
+                }
+                step
+            }
+            fn __step_end(&mut self, step: bool) {
+                if step {
+                    let changes = self.__end();
+                    if !changes.is_empty() {
+                        self.__notify(&changes);
+                        self.__update_live(&changes);
+                        if self.__history.is_enabled() {
+                            self.__history.push(changes);
+                        }
+                    }
+                }
+                self.__history.exit(step);
+            }
+            /// End a step whether or not the code that made its changes
+            /// panicked, so that a panic that is caught does not leave the
+            /// step open and stop every later step from being recorded.
+            fn __step_close<R>(&mut self, step: bool, result: std::thread::Result<R>) -> R {
+                self.__step_end(step);
+                match result {
+                    Ok(r) => r,
+                    Err(panic) => std::panic::resume_unwind(panic),
+                }
+            }
+
+            fn __begin(&mut self) {
+                #( self.#all_names.begin(); )*
+                #( self.#pod_lookup_hashes.begin(); )*
+            }
+            fn __commit(&mut self) {
+                #( self.#all_names.commit(); )*
+                #( self.#pod_lookup_hashes.commit(); )*
+            }
+            fn __end(&mut self) -> #changes {
+                #changes {
+                    #( #all_names: self.#all_names.end(), )*
+                    #( #pod_lookup_hashes: self.#pod_lookup_hashes.end(), )*
+                }
+            }
+            fn __revert(&mut self, changes: #changes) -> #changes {
+                #changes {
+                    #( #all_names: self.#all_names.revert(changes.#all_names), )*
+                    #( #pod_lookup_hashes: self.#pod_lookup_hashes.revert(changes.#pod_lookup_hashes), )*
                 }
             }
         }
 
+        /// A group of changes to the database, which can be reverted.
+        struct #changes {
+            #( #all_names: polygraph::TableChanges<#all_query_types>, )*
+            #( #pod_lookup_hashes: polygraph::LookupChanges<#pod_types>, )*
+        }
+        impl #changes {
+            fn is_empty(&self) -> bool {
+                true #( && self.#all_names.is_empty() )* #( && self.#pod_lookup_hashes.is_empty() )*
+            }
+        }
+
+        /// The live queries over each table of the database.
+        #[derive(Default)]
+        struct #lives {
+            #( #all_names: polygraph::LiveQueries<#name, Key<#all_types>>, )*
+        }
+        impl #lives {
+            fn is_empty(&self) -> bool {
+                true #( && self.#all_names.is_empty() )*
+            }
+        }
+
+        /// The callbacks observing changes to the database.
+        #[derive(Default)]
+        struct #observers {
+            #( #all_names: polygraph::Observers<polygraph::Change<Key<#all_types>>>, )*
+            #( #edge_names: polygraph::Observers<polygraph::EdgeChange<Key<#edge_froms>, Key<#edge_tos>>>, )*
+        }
+        impl #observers {
+            fn is_empty(&self) -> bool {
+                true #( && self.#all_names.is_empty() )* #( && self.#edge_names.is_empty() )*
+            }
+        }
+
         type Set64<K> = tinyset::Set64<K>;
         type KeySet<T> = Set64<Key<T>>;
+        type KeyMap<T, V> = std::collections::HashMap<Key<T>, V>;
 
-        #[derive(Eq,PartialEq,Hash)]
-        pub struct Key<T>(usize, std::marker::PhantomData<T>);
+        /// A key to a row in one of the tables.
+        ///
+        /// Once its row is removed, a key will never refer to another row,
+        /// even if the row is stored in the same place.
+        pub struct Key<T>(polygraph::RawKey, std::marker::PhantomData<T>);
         impl<T> Copy for Key<T> {}
         impl<T> Clone for Key<T> {
             fn clone(&self) -> Self {
                 Key(self.0, self.1)
             }
         }
+        impl<T> PartialEq for Key<T> {
+            fn eq(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
+        impl<T> Eq for Key<T> {}
+        impl<T> std::hash::Hash for Key<T> {
+            fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+                self.0.hash(state)
+            }
+        }
+        impl<T> std::fmt::Debug for Key<T> {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "Key({:?})", self.0)
+            }
+        }
         impl<T> tinyset::Fits64 for Key<T> {
             unsafe fn from_u64(x: u64) -> Self {
-                Key(x as usize, std::marker::PhantomData)
+                Key(tinyset::Fits64::from_u64(x), std::marker::PhantomData)
             }
             fn to_u64(self) -> u64 {
-                self.0.to_u64()
+                tinyset::Fits64::to_u64(self.0)
             }
         }
 
         impl #name {
             #(
+                /// Intern `datum`, returning the key of an equal row if
+                /// there already is one.
                 pub fn #pod_inserts(&mut self, datum: #pod_types) -> Key<#pod_types> {
-                    let idx = self.#pod_names.len();
-                    self.#pod_names.push(#pod_query_types::new(datum.clone()));
-                    self.#pod_lookup_hashes.insert(datum, idx);
-                    Key(idx, std::marker::PhantomData)
+                    match self.#pod_lookups(&datum) {
+                        Some(k) => k,
+                        None => self.#pod_insert_duplicates(datum),
+                    }
+                }
+                /// Insert `datum` as a new row, even if an equal row already
+                /// exists.  Lookups will continue to find the older row while
+                /// it holds an equal value, and then one of the others.
+                pub fn #pod_insert_duplicates(&mut self, datum: #pod_types) -> Key<#pod_types> {
+                    let step = self.__step_begin();
+                    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        let idx = self.#pod_names.insert(#pod_query_types::new(datum.clone()));
+                        if self.#pod_lookup_hashes.get(&datum).is_none() {
+                            self.#pod_lookup_hashes.insert(datum, idx);
+                        }
+                        Key(idx, std::marker::PhantomData)
+                    }));
+                    self.__step_close(step, r)
                 }
             )*
             #(
                 pub fn #key_inserts(&mut self, datum: #key_types) -> Key<#key_types> {
-                    let idx = self.#key_names.len();
-                    self.#key_names.push(#key_query_types::new(datum.clone()));
-                    let k = Key(idx, std::marker::PhantomData);
-                    #key_insert_backrefs
-                    k
+                    let step = self.__step_begin();
+                    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        let k = Key(self.#key_names.next_key(), std::marker::PhantomData);
+                        #key_insert_backrefs
+                        self.#key_names.insert(#key_query_types::new(datum));
+                        k
+                    }));
+                    self.__step_close(step, r)
+                }
+                /// Replace the row at `k`, updating any back references,
+                /// and return its old value.
+                pub fn #key_sets(&mut self, k: Key<#key_types>, datum: #key_types) -> #key_types {
+                    let step = self.__step_begin();
+                    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        let old = std::mem::replace(&mut self.#key_names[k.0].__data, datum);
+                        #key_set_backrefs
+                        old
+                    }));
+                    self.__step_close(step, r)
+                }
+                #key_field_setters
+                #key_data_accessors
+                /// Modify the row at `k` in place, then update the back
+                /// references of any relationship fields that `f` changed.
+                pub fn #key_modifies<R>(&mut self, k: Key<#key_types>, f: impl FnOnce(&mut #key_types) -> R) -> R {
+                    let step = self.__step_begin();
+                    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        #key_modify_before
+                        let r = f(&mut self.#key_names[k.0].__data);
+                        #key_modify_after
+                        r
+                    }));
+                    self.__step_close(step, r)
+                }
+                /// Like the method without `try_`, but fails rather than
+                /// panicking if `datum` holds a key that does not refer to a
+                /// row.
+                pub fn #key_try_inserts(&mut self, datum: #key_types) -> Result<Key<#key_types>, polygraph::Error> {
+                    #key_datum_checks
+                    Ok(self.#key_inserts(datum))
+                }
+                /// Like the method without `try_`, but fails rather than
+                /// panicking if a key does not refer to a row.
+                pub fn #key_try_sets(&mut self, k: Key<#key_types>, datum: #key_types) -> Result<#key_types, polygraph::Error> {
+                    self.__check(AnyKey::#key_type_idents(k))?;
+                    #key_datum_checks
+                    Ok(self.#key_sets(k, datum))
                 }
-                pub fn #key_sets(&mut self, k: Key<#key_types>, datum: #key_types) {
-                    let old = std::mem::replace(&mut self.#key_names[k.0], #key_query_types::new(datum));
-                    // FIXME need to modify any back references.
+                /// Like the method without `try_`, but fails rather than
+                /// panicking if a key does not refer to a row, including a
+                /// key that `f` stores in the row, in which case the row is
+                /// left unchanged.
+                pub fn #key_try_modifies<R>(&mut self, k: Key<#key_types>, f: impl FnOnce(&mut #key_types) -> R) -> Result<R, polygraph::Error> {
+                    self.__check(AnyKey::#key_type_idents(k))?;
+                    let mut datum = self.#key_names[k.0].__data.clone();
+                    let r = f(&mut datum);
+                    #key_datum_checks
+                    self.#key_sets(k, datum);
+                    Ok(r)
                 }
             )*
             #(
@@ -718,7 +2549,177 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                     //     .map(|(i,x)| Key(i, std::marker::PhantomData))
                     //     .next()
                 }
+                /// Let lookups of `datum` find some other row that holds
+                /// it, once the row they found is gone or changed.
+                fn #pod_rehashes(&mut self, datum: #pod_types) {
+                    let other = self.#pod_names.iter()
+                        .find(|(_, row)| row.__data == datum)
+                        .map(|(i, _)| i);
+                    if let Some(other) = other {
+                        self.#pod_lookup_hashes.insert(datum, other);
+                    }
+                }
+            )*
+            #(
+                /// Change the value of the row at `k`, returning its old
+                /// value.  This fails if some other row already has the new
+                /// value, since that would stop it from being interned.
+                pub fn #pod_sets(&mut self, k: Key<#pod_types>, datum: #pod_types) -> Result<#pod_types, polygraph::Error> {
+                    self.__check(AnyKey::#pod_types(k))?;
+                    match self.#pod_lookups(&datum) {
+                        Some(other) if other != k => {
+                            return Err(polygraph::Error::Duplicate { table: #pod_type_names });
+                        }
+                        _ => (),
+                    }
+                    let step = self.__step_begin();
+                    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        let duplicated = self.#pod_lookup_hashes.len() < self.#pod_names.len();
+                        let old = std::mem::replace(&mut self.#pod_names[k.0].__data, datum.clone());
+                        if self.#pod_lookup_hashes.get(&old) == Some(&k.0) {
+                            self.#pod_lookup_hashes.remove(&old);
+                            if duplicated {
+                                self.#pod_rehashes(old.clone());
+                            }
+                        }
+                        self.#pod_lookup_hashes.insert(datum, k.0);
+                        Ok(old)
+                    }));
+                    self.__step_close(step, r)
+                }
+                /// Change the value of the row at `k`.  If some other row
+                /// already has the new value, everything that refers to `k`
+                /// is pointed at that row instead and `k` is removed.
+                /// Returns the key that now holds the value.
+                pub fn #pod_set_or_merges(&mut self, k: Key<#pod_types>, datum: #pod_types) -> Key<#pod_types> {
+                    let step = self.__step_begin();
+                    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        let k = match self.#pod_lookups(&datum) {
+                            Some(other) if other != k => {
+                                self.#pod_repoint_fns(k, other);
+                                self.#pod_removes(k).expect("nothing should refer to a merged row");
+                                other
+                            }
+                            _ => {
+                                self.#pod_sets(k, datum).expect("there is no duplicate");
+                                k
+                            }
+                        };
+                        k
+                    }));
+                    self.__step_close(step, r)
+                }
+                /// Like the method without `try_`, but fails rather than
+                /// panicking if `k` does not refer to a row.
+                pub fn #pod_try_set_or_merges(&mut self, k: Key<#pod_types>, datum: #pod_types) -> Result<Key<#pod_types>, polygraph::Error> {
+                    self.__check(AnyKey::#pod_types(k))?;
+                    Ok(self.#pod_set_or_merges(k, datum))
+                }
+            )*
+            #(
+                /// Merge the row `discard` into `keep`: everything that
+                /// refers to `discard` is pointed at `keep` instead, and then
+                /// `discard` is removed and its value returned.
+                pub fn #all_merges(&mut self, keep: Key<#all_types>, discard: Key<#all_types>) -> #all_types {
+                    assert_ne!(keep, discard, "cannot merge a row with itself");
+                    let step = self.__step_begin();
+                    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        self.#all_repoint_fns(discard, keep);
+                        self.#all_removes(discard).expect("nothing should refer to a merged row")
+                    }));
+                    self.__step_close(step, r)
+                }
+                /// Like the method without `try_`, but fails rather than
+                /// panicking if a key does not refer to a row, or if `keep`
+                /// and `discard` are the same row.
+                pub fn #all_try_merges(&mut self, keep: Key<#all_types>, discard: Key<#all_types>) -> Result<#all_types, polygraph::Error> {
+                    self.__check(AnyKey::#all_types(keep))?;
+                    self.__check(AnyKey::#all_types(discard))?;
+                    if keep == discard {
+                        return Err(polygraph::Error::SelfMerge { table: #all_type_names });
+                    }
+                    Ok(self.#all_merges(keep, discard))
+                }
+                /// Point every reference to `from` at `to` instead.
+                fn #all_repoint_fns(&mut self, from: Key<#all_types>, to: Key<#all_types>) {
+                    #repoints
+                }
+            )*
+            #(
+                /// The row at `k`, or `None` if it has been removed.
+                pub fn #all_gets(&self, k: Key<#all_types>) -> Option<&#all_query_types> {
+                    self.#all_names.get(k.0)
+                }
             )*
+            #(
+                /// Remove the row at `k` and return its value.
+                ///
+                /// Rows that refer to it are removed too, or have their
+                /// reference cleared, according to their `on_delete`
+                /// setting.  If any of them is `restrict`, nothing is
+                /// removed and an error is returned.
+                pub fn #all_removes(&mut self, k: Key<#all_types>) -> Result<#all_types, polygraph::Error> {
+                    self.__check(AnyKey::#all_types(k))?;
+                    let doomed = self.__doomed(AnyKey::#all_types(k))?;
+                    let step = self.__step_begin();
+                    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        self.__unlink(&doomed);
+                        for &row in doomed.iter().filter(|&&row| row != AnyKey::#all_types(k)) {
+                            self.__free(row);
+                        }
+                        let value = #free_rows.__data;
+                        Ok(value)
+                    }));
+                    self.__step_close(step, r)
+                }
+            )*
+
+            /// Check that `row` is in the database.
+            fn __check(&self, row: AnyKey) -> Result<(), polygraph::Error> {
+                match row {
+                    #( AnyKey::#all_types(k) => self.#all_names.check(k.0, #all_type_names), )*
+                }
+            }
+            /// Find every row that goes away along with `row`, and check
+            /// that nothing forbids removing them.
+            #[allow(unused_variables)]
+            fn __doomed(&self, row: AnyKey) -> Result<std::collections::HashSet<AnyKey>, polygraph::Error> {
+                let mut doomed = std::collections::HashSet::new();
+                let mut todo = vec![row];
+                while let Some(row) = todo.pop() {
+                    if doomed.insert(row) {
+                        match row {
+                            #( AnyKey::#all_types(k) => { #cascade_arms } )*
+                        }
+                    }
+                }
+                for &row in doomed.iter() {
+                    match row {
+                        #( AnyKey::#all_types(k) => { #restrict_arms } )*
+                    }
+                }
+                Ok(doomed)
+            }
+            /// Clear every reference to or from the `doomed` rows.
+            #[allow(unused_variables)]
+            fn __unlink(&mut self, doomed: &std::collections::HashSet<AnyKey>) {
+                for &row in doomed.iter() {
+                    match row {
+                        #( AnyKey::#all_types(k) => { #unlink_arms } )*
+                    }
+                }
+            }
+            fn __free(&mut self, row: AnyKey) {
+                match row {
+                    #( AnyKey::#all_types(k) => { #free_rows; } )*
+                }
+            }
+        }
+
+        /// A key into any one of the tables.
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+        enum AnyKey {
+            #( #all_types(Key<#all_types>), )*
         }
 
         #(
@@ -735,6 +2736,106 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                 }
             }
         )*
+        impl<T> Key<T> {
+            /// This key along with the database, to navigate from its row
+            /// to related rows.
+            pub fn r(self, database: &#name) -> Ref<'_, T> {
+                Ref {
+                    db: database,
+                    key: self,
+                }
+            }
+        }
+
+        /// A row along with the database it is in, which dereferences to
+        /// the row and has a method for each relationship of the row, so
+        /// that related rows can be reached without passing the database
+        /// around.
+        pub struct Ref<'a, T> {
+            db: &'a #name,
+            key: Key<T>,
+        }
+        impl<'a, T> Copy for Ref<'a, T> {}
+        impl<'a, T> Clone for Ref<'a, T> {
+            fn clone(&self) -> Self {
+                *self
+            }
+        }
+        impl<'a, T> PartialEq for Ref<'a, T> {
+            fn eq(&self, other: &Self) -> bool {
+                self.key == other.key && std::ptr::eq(self.db, other.db)
+            }
+        }
+        impl<'a, T> Eq for Ref<'a, T> {}
+        impl<'a, T> std::fmt::Debug for Ref<'a, T> {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "Ref({:?})", self.key.0)
+            }
+        }
+        impl<'a, T> Ref<'a, T> {
+            /// The key of the row.
+            pub fn key(self) -> Key<T> {
+                self.key
+            }
+        }
+
+        /// A set of rows along with the database they are in.
+        pub struct RefSet<'a, T> {
+            db: &'a #name,
+            set: &'a KeySet<T>,
+        }
+        impl<'a, T> Copy for RefSet<'a, T> {}
+        impl<'a, T> Clone for RefSet<'a, T> {
+            fn clone(&self) -> Self {
+                *self
+            }
+        }
+        impl<'a, T> std::fmt::Debug for RefSet<'a, T> {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.debug_set().entries(self.set.iter()).finish()
+            }
+        }
+        impl<'a, T> RefSet<'a, T> {
+            /// The keys of the rows.
+            pub fn keys(self) -> &'a KeySet<T> {
+                self.set
+            }
+            /// The number of rows.
+            pub fn len(self) -> usize {
+                self.set.len()
+            }
+            /// Whether there are no rows.
+            pub fn is_empty(self) -> bool {
+                self.set.len() == 0
+            }
+            /// Whether the row with key `k` is in the set.
+            pub fn contains(self, k: Key<T>) -> bool {
+                self.set.contains(k)
+            }
+            /// Iterate over the rows.
+            pub fn iter(self) -> impl Iterator<Item = Ref<'a, T>> + 'a {
+                let db = self.db;
+                self.set.iter().map(move |key| Ref { db, key })
+            }
+        }
+
+        #(
+            impl<'a> std::ops::Deref for Ref<'a, #all_types> {
+                type Target = #all_query_types;
+                fn deref(&self) -> &Self::Target {
+                    &self.db[self.key]
+                }
+            }
+            impl<'a> Ref<'a, #all_types> {
+                #ref_methods
+            }
+        )*
+
+        impl polygraph::Database for #name {
+            fn snapshot(&self) -> polygraph::Snapshot<Self> {
+                #name::snapshot(self)
+            }
+        }
         #(
             impl std::ops::Index<Key<#key_types>> for #name {
                 type Output = #key_query_types;
@@ -751,6 +2852,14 @@ pub fn schema(raw_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                 }
             }
         )*
+        #(
+            impl std::ops::Index<polygraph::Live<Key<#all_types>>> for #name {
+                type Output = KeySet<#all_types>;
+                fn index(&self, index: polygraph::Live<Key<#all_types>>) -> &Self::Output {
+                    self.__live.#all_names.result(index).expect("no such live query")
+                }
+            }
+        )*
     };
     // println!("\n\n\noutput is\n\n{}", output.to_string());
     output.into()
@@ -0,0 +1,74 @@
+//! Errors from modifying a database.
+
+/// The reason a change to a database was refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A key referred to a row that has been removed, or whose insertion
+    /// was undone.
+    StaleKey {
+        /// The table the key is for.
+        table: &'static str,
+    },
+    /// A key referred to a row that never existed, so it was most likely
+    /// made by a different database.
+    ForeignKey {
+        /// The table the key is for.
+        table: &'static str,
+    },
+    /// A row could not be removed because a field marked
+    /// `#[polygraph(on_delete = "restrict")]` still refers to it.
+    Restricted {
+        /// The table holding the reference.
+        table: &'static str,
+        /// The field holding the reference.
+        field: &'static str,
+    },
+    /// A row could not be given a value that another row of an interned
+    /// table already has.
+    Duplicate {
+        /// The table with the duplicate.
+        table: &'static str,
+    },
+    /// An index was past the end of a list of keys.
+    OutOfBounds {
+        /// The table holding the list.
+        table: &'static str,
+        /// The field holding the list.
+        field: &'static str,
+        /// The index.
+        index: usize,
+        /// The length of the list.
+        len: usize,
+    },
+    /// A row could not be merged into itself.
+    SelfMerge {
+        /// The table of the row.
+        table: &'static str,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::StaleKey { table } => write!(f, "{} row has been removed", table),
+            Error::ForeignKey { table } => write!(f, "{} row is not in this database", table),
+            Error::Restricted { table, field } => {
+                write!(f, "row is still referenced by {}::{}", table, field)
+            }
+            Error::Duplicate { table } => write!(f, "{} already has this value", table),
+            Error::OutOfBounds {
+                table,
+                field,
+                index,
+                len,
+            } => write!(
+                f,
+                "index {} is past the end of {}::{}, of length {}",
+                index, table, field, len
+            ),
+            Error::SelfMerge { table } => write!(f, "{} row cannot be merged with itself", table),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -37,6 +37,12 @@ pub mod tree {
         pub struct Dog {
             pub name: String,
         }
+        /// A `Nickname` is removed along with the `Person` it belongs to.
+        pub struct Nickname {
+            #[polygraph(on_delete = "cascade")]
+            pub person: Key<Person>,
+            pub name: String,
+        }
     }
 
     #[test]
@@ -98,11 +104,1546 @@ pub mod tree {
         assert!(db[me].father_of.contains(kid));
         assert!(!db[me].father_of.contains(wife));
 
-        // db.set_person(wife, Person {
-        //     surname: roundy,
-        //     name: "Monica".to_string()
-        // });
-        // assert_eq!(wife.d(&db).surname.d(&db).0, "Roundy");
+        let old = db.set_person(
+            wife,
+            Person {
+                last_name: roundy,
+                father: None,
+                mother: None,
+                name: "Monica".to_string(),
+                dog: [mickey].iter().cloned().collect(),
+            },
+        );
+        assert_eq!(old.last_name.d(&db).0, "Maiden");
+        assert_eq!(wife.d(&db).last_name.d(&db).0, "Roundy");
+        assert!(roundy.d(&db).last_name_of.contains(wife));
+        assert!(!maiden_name.d(&db).last_name_of.contains(wife));
+        assert!(mickey.d(&db).dog_of.contains(wife));
+        assert!(!minnie.d(&db).dog_of.contains(wife));
+        // The back references *to* the row we replaced are untouched.
+        assert!(wife.d(&db).mother_of.contains(kid));
+
+        db.set_person(
+            kid,
+            Person {
+                last_name: roundy,
+                father: Some(wife),
+                mother: Some(me),
+                name: "Kid".to_string(),
+                dog: my_dogs.clone(),
+            },
+        );
+        assert!(!me.d(&db).father_of.contains(kid));
+        assert!(me.d(&db).mother_of.contains(kid));
+        assert!(wife.d(&db).father_of.contains(kid));
+        assert!(!wife.d(&db).mother_of.contains(kid));
+        assert!(minnie.d(&db).dog_of.contains(kid));
+    }
+
+    #[test]
+    fn remove() {
+        let mut db = Tree::new();
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: [mickey].iter().cloned().collect(),
+        });
+        let kid = db.insert_person(Person {
+            last_name: roundy,
+            father: Some(me),
+            mother: None,
+            name: "Kid".to_string(),
+            dog: [mickey].iter().cloned().collect(),
+        });
+        let nick = db.insert_nickname(Nickname {
+            person: me,
+            name: "Dave".to_string(),
+        });
+
+        // A `Key` defaults to restrict.
+        assert_eq!(
+            db.remove_surname(roundy).err(),
+            Some(polygraph::Error::Restricted {
+                table: "Person",
+                field: "last_name",
+            })
+        );
+        assert!(db[roundy].last_name_of.contains(me));
+
+        // Option<Key> and KeySet default to set_null.
+        assert_eq!(db.remove_dog(mickey).unwrap().name, "Mickey");
+        assert!(!db[me].dog.contains(mickey));
+        assert!(!db[kid].dog.contains(mickey));
+        assert_eq!(db.len_dog(), 0);
+
+        assert_eq!(db.remove_person(me).unwrap().name, "David");
+        assert_eq!(db[kid].father, None);
+        assert!(!db[roundy].last_name_of.contains(me));
+        assert!(db[roundy].last_name_of.contains(kid));
+        assert!(db.get_nickname(nick).is_none());
+        assert_eq!(db.len_person(), 1);
+
+        db.remove_person(kid).unwrap();
+        assert_eq!(db.remove_surname(roundy).unwrap().0, "Roundy");
+        assert_eq!(db.lookup_surname(&Surname("Roundy".to_string())), None);
+    }
+
+    #[test]
+    fn stale_keys() {
+        let mut db = Tree::new();
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        let dogs: tinyset::Set64<_> = [mickey].iter().cloned().collect();
+        db.remove_dog(mickey).unwrap();
+        assert!(db.get_dog(mickey).is_none());
+
+        // The new dog reuses the storage of the old one, but the old key
+        // still doesn't find anything.
+        let minnie = db.insert_dog(Dog {
+            name: "Minnie".to_string(),
+        });
+        assert_ne!(mickey, minnie);
+        assert!(db.get_dog(mickey).is_none());
+        assert_eq!(db.get_dog(minnie).unwrap().name, "Minnie");
+        assert!(dogs.contains(mickey));
+        assert!(!dogs.contains(minnie));
+    }
+
+    #[test]
+    fn field_setters() {
+        let mut db = Tree::new();
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let smith = db.insert_surname(Surname("Smith".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: KeySet::new(),
+        });
+        let kid = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "Kid".to_string(),
+            dog: KeySet::new(),
+        });
+
+        assert_eq!(db.set_person_father(kid, Some(me)), None);
+        assert!(db[me].father_of.contains(kid));
+        assert_eq!(db.set_person_father(kid, None), Some(me));
+        assert!(!db[me].father_of.contains(kid));
+
+        assert_eq!(db.set_person_last_name(kid, smith), roundy);
+        assert!(!db[roundy].last_name_of.contains(kid));
+        assert!(db[smith].last_name_of.contains(kid));
+
+        assert!(db.add_person_dog(me, mickey));
+        assert!(!db.add_person_dog(me, mickey));
+        assert!(db[me].dog.contains(mickey));
+        assert!(db[mickey].dog_of.contains(me));
+        assert!(db.remove_person_dog(me, mickey));
+        assert!(!db.remove_person_dog(me, mickey));
+        assert!(!db[me].dog.contains(mickey));
+        assert!(!db[mickey].dog_of.contains(me));
+    }
+
+    #[test]
+    fn modify() {
+        let mut db = Tree::new();
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: KeySet::new(),
+        });
+        let kid = db.insert_person(Person {
+            last_name: roundy,
+            father: Some(me),
+            mother: None,
+            name: "Kid".to_string(),
+            dog: KeySet::new(),
+        });
+
+        let old_name = db.modify_person(kid, |p| {
+            p.father = None;
+            p.mother = Some(me);
+            p.dog.insert(mickey);
+            std::mem::replace(&mut p.name, "Child".to_string())
+        });
+        assert_eq!(old_name, "Kid");
+        assert_eq!(db[kid].name, "Child");
+
+        db.with_person_name(kid, |n| n.push_str("hood"));
+        assert_eq!(db[kid].name, "Childhood");
+        let nick = db.insert_nickname(Nickname {
+            person: kid,
+            name: "Kiddo".to_string(),
+        });
+        db.with_nickname_name(nick, |n| n.make_ascii_uppercase());
+        assert_eq!(db[nick].name, "KIDDO");
+        assert!(!db[me].father_of.contains(kid));
+        assert!(db[me].mother_of.contains(kid));
+        assert!(db[mickey].dog_of.contains(kid));
+    }
+
+    #[test]
+    fn interning() {
+        let mut db = Tree::new();
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        assert_eq!(db.insert_surname(Surname("Roundy".to_string())), roundy);
+        assert_eq!(db.len_surname(), 1);
+
+        let copy = db.insert_duplicate_surname(Surname("Roundy".to_string()));
+        assert_ne!(copy, roundy);
+        assert_eq!(db.len_surname(), 2);
+        assert_eq!(
+            db.lookup_surname(&Surname("Roundy".to_string())),
+            Some(roundy)
+        );
+
+        // Once the row lookups find changes or goes, they find its
+        // duplicate.
+        let other = db.insert_duplicate_surname(Surname("Roundy".to_string()));
+        db.set_surname(roundy, Surname("Rowndy".to_string()))
+            .unwrap();
+        let found = db.lookup_surname(&Surname("Roundy".to_string())).unwrap();
+        assert!(found == copy || found == other);
+        db.remove_surname(found).unwrap();
+        let left = if found == copy { other } else { copy };
+        assert_eq!(
+            db.lookup_surname(&Surname("Roundy".to_string())),
+            Some(left)
+        );
+        db.remove_surname(left).unwrap();
+        assert_eq!(db.lookup_surname(&Surname("Roundy".to_string())), None);
+        assert_eq!(
+            db.lookup_surname(&Surname("Rowndy".to_string())),
+            Some(roundy)
+        );
+    }
+
+    #[test]
+    fn set_pod() {
+        let mut db = Tree::new();
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let rowndy = db.insert_surname(Surname("Rowndy".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: KeySet::new(),
+        });
+        let typo = db.insert_person(Person {
+            last_name: rowndy,
+            father: None,
+            mother: None,
+            name: "Kid".to_string(),
+            dog: KeySet::new(),
+        });
+
+        assert_eq!(
+            db.set_surname(rowndy, Surname("Roundy".to_string())).err(),
+            Some(polygraph::Error::Duplicate { table: "Surname" })
+        );
+        let old = db
+            .set_surname(rowndy, Surname("Rondy".to_string()))
+            .unwrap();
+        assert_eq!(old.0, "Rowndy");
+        assert_eq!(db.lookup_surname(&old), None);
+        assert_eq!(
+            db.lookup_surname(&Surname("Rondy".to_string())),
+            Some(rowndy)
+        );
+
+        let merged = db.set_or_merge_surname(rowndy, Surname("Roundy".to_string()));
+        assert_eq!(merged, roundy);
+        assert!(db.get_surname(rowndy).is_none());
+        assert_eq!(db[typo].last_name, roundy);
+        assert!(db[roundy].last_name_of.contains(me));
+        assert!(db[roundy].last_name_of.contains(typo));
+        assert_eq!(db.lookup_surname(&Surname("Rondy".to_string())), None);
+    }
+
+    #[test]
+    fn merge() {
+        let mut db = Tree::new();
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: KeySet::new(),
+        });
+        let also_me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "Dave".to_string(),
+            dog: [mickey].iter().cloned().collect(),
+        });
+        let kid = db.insert_person(Person {
+            last_name: roundy,
+            father: Some(also_me),
+            mother: None,
+            name: "Kid".to_string(),
+            dog: KeySet::new(),
+        });
+        let nick = db.insert_nickname(Nickname {
+            person: also_me,
+            name: "Dad".to_string(),
+        });
+
+        assert_eq!(db.merge_person(me, also_me).name, "Dave");
+        assert!(db.get_person(also_me).is_none());
+        assert_eq!(db[kid].father, Some(me));
+        assert!(db[me].father_of.contains(kid));
+        assert_eq!(db[nick].person, me);
+        assert!(db[me].person_of.contains(nick));
+        assert!(!db[mickey].dog_of.contains(also_me));
+        assert!(!db[roundy].last_name_of.contains(also_me));
+        assert!(db[roundy].last_name_of.contains(me));
+    }
+
+    #[test]
+    fn transaction() {
+        let mut db = Tree::new();
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: [mickey].iter().cloned().collect(),
+        });
+
+        let result: Result<(), &str> = db.transaction(|db| {
+            let kid = db.insert_person(Person {
+                last_name: roundy,
+                father: Some(me),
+                mother: None,
+                name: "Kid".to_string(),
+                dog: KeySet::new(),
+            });
+            db.insert_surname(Surname("Smith".to_string()));
+            db.remove_dog(mickey).unwrap();
+            assert!(db[me].father_of.contains(kid));
+            Err("oops")
+        });
+        assert_eq!(result, Err("oops"));
+        assert_eq!(db.len_person(), 1);
+        assert!(db[me].father_of.is_empty());
+        assert!(db[me].dog.contains(mickey));
+        assert!(db[mickey].dog_of.contains(me));
+        assert_eq!(db.lookup_surname(&Surname("Smith".to_string())), None);
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _: Result<(), ()> = db.transaction(|db| {
+                db.set_person_father(me, Some(me));
+                panic!("oops");
+            });
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(db[me].father, None);
+        assert!(db[me].father_of.is_empty());
+
+        let kid = db
+            .transaction(|db| {
+                let kid = db.insert_person(Person {
+                    last_name: roundy,
+                    father: Some(me),
+                    mother: None,
+                    name: "Kid".to_string(),
+                    dog: KeySet::new(),
+                });
+                // An inner transaction can fail without spoiling the outer one.
+                let inner: Result<(), ()> = db.transaction(|db| {
+                    db.remove_person(kid).unwrap();
+                    Err(())
+                });
+                assert!(inner.is_err());
+                Ok::<_, ()>(kid)
+            })
+            .unwrap();
+        assert!(db[me].father_of.contains(kid));
+        assert_eq!(db.len_person(), 2);
+
+        // When both fail, nothing the inner one undid comes back.
+        let outer: Result<(), ()> = db.transaction(|db| {
+            let inner: Result<(), ()> = db.transaction(|db| {
+                db.set_dog(
+                    mickey,
+                    Dog {
+                        name: "Inner".to_string(),
+                    },
+                )
+                .unwrap();
+                db.set_surname(roundy, Surname("X".to_string())).unwrap();
+                Err(())
+            });
+            assert!(inner.is_err());
+            Err(())
+        });
+        assert!(outer.is_err());
+        assert_eq!(db[mickey].name, "Mickey");
+        assert_eq!(db[roundy].0, "Roundy");
+        assert_eq!(
+            db.lookup_surname(&Surname("Roundy".to_string())),
+            Some(roundy)
+        );
+        assert_eq!(db.lookup_surname(&Surname("X".to_string())), None);
+
+        // Keys handed out by a transaction that is rolled back never find
+        // a later row, even one that reuses the same slot.
+        let mut leaked = Vec::new();
+        let result: Result<(), ()> = db.transaction(|db| {
+            leaked.push(db.insert_dog(Dog {
+                name: "Rex".to_string(),
+            }));
+            db.remove_person(kid).unwrap();
+            leaked.push(db.insert_dog(Dog {
+                name: "Spot".to_string(),
+            }));
+            Err(())
+        });
+        assert!(result.is_err());
+        let fido = db.insert_dog(Dog {
+            name: "Fido".to_string(),
+        });
+        let rover = db.insert_dog(Dog {
+            name: "Rover".to_string(),
+        });
+        for &dog in leaked.iter() {
+            assert!(dog != fido && dog != rover);
+            assert!(db.get_dog(dog).is_none());
+        }
+        db.remove_person(kid).unwrap();
+        let other = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "Other".to_string(),
+            dog: KeySet::new(),
+        });
+        assert!(db.get_person(kid).is_none());
+        assert_eq!(db[other].name, "Other");
+
+        // Starting to watch the database partway through a transaction
+        // does not keep its later changes from being rolled back.
+        let result: Result<(), ()> = db.transaction(|db| {
+            let id = db.observe_dog(|_| {});
+            db.enable_history();
+            db.insert_person(Person {
+                last_name: roundy,
+                father: Some(other),
+                mother: None,
+                name: "Late".to_string(),
+                dog: [fido].iter().cloned().collect(),
+            });
+            db.unobserve(id);
+            Err(())
+        });
+        assert!(result.is_err());
+        assert_eq!(db.len_person(), 2);
+        assert!(db[other].father_of.is_empty());
+        assert!(db[fido].dog_of.is_empty());
+        assert!(db[roundy].last_name_of.contains(other));
+        assert_eq!(db[roundy].last_name_of.len(), 2);
+        assert!(!db.undo());
+    }
+    #[test]
+    fn history() {
+        let mut db = Tree::new();
+        db.enable_history();
+        assert!(!db.undo());
+
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: KeySet::new(),
+        });
+        db.checkpoint("one person");
+        let kid = db.insert_person(Person {
+            last_name: roundy,
+            father: Some(me),
+            mother: None,
+            name: "Kid".to_string(),
+            dog: KeySet::new(),
+        });
+        db.with_person_name(kid, |n| *n = "Younger".to_string());
+        assert!(db[me].father_of.contains(kid));
+
+        assert!(db.undo());
+        assert_eq!(db[kid].name, "Kid");
+        assert!(db.undo());
+        assert!(db.get_person(kid).is_none());
+        assert!(db[me].father_of.is_empty());
+        assert!(db[roundy].last_name_of.contains(me));
+        assert!(!db[roundy].last_name_of.contains(kid));
+
+        assert!(db.redo());
+        assert!(db.redo());
+        assert!(!db.redo());
+        assert_eq!(db[kid].name, "Younger");
+        assert!(db[me].father_of.contains(kid));
+
+        // A removal is undone together with all of its side effects.
+        db.remove_person(kid).unwrap();
+        assert!(db.undo());
+        assert_eq!(db[kid].father, Some(me));
+        assert!(db[me].father_of.contains(kid));
+
+        // A transaction is a single step, and one that fails leaves none.
+        let _: Result<(), ()> = db.transaction(|db| {
+            db.set_person_father(kid, None);
+            db.set_person_mother(kid, Some(me));
+            Ok(())
+        });
+        let _: Result<(), ()> = db.transaction(|db| {
+            db.set_person_mother(kid, None);
+            Err(())
+        });
+        assert_eq!(db[kid].mother, Some(me));
+        assert!(db.undo());
+        assert_eq!(db[kid].father, Some(me));
+        assert_eq!(db[kid].mother, None);
+        assert!(db[me].mother_of.is_empty());
+
+        // Undoing a transaction does not bring back what an inner
+        // transaction rolled back.
+        let _: Result<(), ()> = db.transaction(|db| {
+            db.set_person_father(kid, None);
+            let inner: Result<(), ()> = db.transaction(|db| {
+                db.set_surname(roundy, Surname("Inner".to_string()))
+                    .unwrap();
+                Err(())
+            });
+            assert!(inner.is_err());
+            Ok(())
+        });
+        assert!(db.undo());
+        assert_eq!(db[kid].father, Some(me));
+        assert_eq!(db[roundy].0, "Roundy");
+        assert_eq!(
+            db.lookup_surname(&Surname("Roundy".to_string())),
+            Some(roundy)
+        );
+
+        assert!(db.undo_to("one person"));
+        assert_eq!(db.len_person(), 1);
+        assert!(db[me].father_of.is_empty());
+        assert!(!db.undo_to("never made"));
+
+        // Making a change forgets whatever had been undone.
+        db.insert_surname(Surname("Smith".to_string()));
+        assert!(!db.redo());
+        assert!(db.undo());
+        assert_eq!(db.lookup_surname(&Surname("Smith".to_string())), None);
+
+        // The key of an undone insert never finds a later row.
+        let other = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "Other".to_string(),
+            dog: KeySet::new(),
+        });
+        assert!(other != kid);
+        assert!(db.get_person(kid).is_none());
+        assert!(db.undo());
+        let another = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "Another".to_string(),
+            dog: KeySet::new(),
+        });
+        assert!(another != kid && another != other);
+        assert!(db.get_person(other).is_none());
+        assert_eq!(
+            db.try_set_person_father(other, None),
+            Err(polygraph::Error::StaleKey { table: "Person" })
+        );
+        assert_eq!(db[another].name, "Another");
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut db = Tree::new();
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let people: Vec<_> = (0..200)
+            .map(|i| {
+                db.insert_person(Person {
+                    last_name: roundy,
+                    father: None,
+                    mother: None,
+                    name: format!("Person {}", i),
+                    dog: KeySet::new(),
+                })
+            })
+            .collect();
+        for i in 0..100 {
+            db.insert_dog(Dog {
+                name: format!("Dog {}", i),
+            });
+        }
+
+        let before = db.snapshot();
+        db.with_person_name(people[150], |n| *n = "Changed".to_string());
+        db.set_person_father(people[3], Some(people[2]));
+        db.remove_person(people[199]).unwrap();
+        let smith = db.insert_surname(Surname("Smith".to_string()));
+        let fido = db.insert_dog(Dog {
+            name: "Fido".to_string(),
+        });
+        db.remove_dog(
+            db.lookup_dog(&Dog {
+                name: "Dog 7".to_string(),
+            })
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(before[people[150]].name, "Person 150");
+        assert_eq!(before[people[3]].father, None);
+        assert!(before[people[2]].father_of.is_empty());
+        assert_eq!(before.person.len(), 200);
+        assert_eq!(before[people[199]].name, "Person 199");
+        assert!(before.get_surname(smith).is_none());
+        assert_eq!(
+            before.lookup_dog(&Dog {
+                name: "Fido".to_string()
+            }),
+            None
+        );
+        assert!(before.get_dog(fido).is_none());
+        assert!(before
+            .lookup_dog(&Dog {
+                name: "Dog 7".to_string(),
+            })
+            .is_some());
+
+        assert_eq!(db[people[150]].name, "Changed");
+        assert!(db[people[2]].father_of.contains(people[3]));
+        assert_eq!(db.len_person(), 199);
+        assert_eq!(
+            db.lookup_dog(&Dog {
+                name: "Fido".to_string()
+            }),
+            Some(fido)
+        );
+        for i in 0..100 {
+            let dog = Dog {
+                name: format!("Dog {}", i),
+            };
+            assert_eq!(db.lookup_dog(&dog).is_some(), i != 7);
+            assert!(before.lookup_dog(&dog).is_some());
+        }
+
+        let again = before.clone();
+        assert_eq!(again.person.len(), 200);
+    }
+    #[test]
+    fn observe() {
+        use polygraph::{Change, EdgeChange};
+        use std::sync::mpsc::channel;
+
+        let mut db = Tree::new();
+        let (people_tx, people) = channel();
+        let (fathers_tx, fathers) = channel();
+        let (dogs_tx, dogs) = channel();
+        db.observe_person(move |c| people_tx.send(*c).unwrap());
+        db.observe_person_father(move |c| fathers_tx.send(*c).unwrap());
+        let dogs_id = db.observe_person_dog(move |c| dogs_tx.send(*c).unwrap());
+
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: KeySet::new(),
+        });
+        assert_eq!(
+            people.try_iter().collect::<Vec<_>>(),
+            [Change::Inserted(me)]
+        );
+        assert_eq!(fathers.try_iter().count(), 0);
+
+        let kid = db.insert_person(Person {
+            last_name: roundy,
+            father: Some(me),
+            mother: None,
+            name: "Kid".to_string(),
+            dog: KeySet::new(),
+        });
+        // Gaining a back reference counts as an update.
+        let mut changes = people.try_iter().collect::<Vec<_>>();
+        changes.sort_by_key(|c| c != &Change::Inserted(kid));
+        assert_eq!(changes, [Change::Inserted(kid), Change::Updated(me)]);
+        assert_eq!(
+            fathers.try_iter().collect::<Vec<_>>(),
+            [EdgeChange::Added(kid, me)]
+        );
+
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        db.add_person_dog(kid, mickey);
+        assert_eq!(
+            dogs.try_iter().collect::<Vec<_>>(),
+            [EdgeChange::Added(kid, mickey)]
+        );
+        people.try_iter().count();
+
+        // Changing plain data reports an update once the change is made.
+        let (names_tx, names) = channel();
+        let names_id = db.observe_person(move |c| names_tx.send(*c).unwrap());
+        db.with_person_name(kid, |n| n.push_str("do"));
+        assert_eq!(names.try_iter().collect::<Vec<_>>(), [Change::Updated(kid)]);
+        assert_eq!(db[kid].name, "Kiddo");
+        db.unobserve(names_id);
+        people.try_iter().count();
+
+        // A transaction reports its changes once it is done, and a
+        // transaction that fails reports nothing.
+        let _: Result<(), ()> = db.transaction(|db| {
+            db.set_person_father(kid, None);
+            assert_eq!(fathers.try_iter().count(), 0);
+            Ok(())
+        });
+        assert_eq!(
+            fathers.try_iter().collect::<Vec<_>>(),
+            [EdgeChange::Removed(kid, me)]
+        );
+        people.try_iter().count();
+        let _: Result<(), ()> = db.transaction(|db| {
+            db.set_person_father(kid, Some(me));
+            Err(())
+        });
+        assert_eq!(fathers.try_iter().count(), 0);
+        assert_eq!(people.try_iter().count(), 0);
+        // Nor does one inside a transaction that succeeds.
+        let _: Result<(), ()> = db.transaction(|db| {
+            let _: Result<(), ()> = db.transaction(|db| {
+                db.set_person_father(kid, Some(me));
+                Err(())
+            });
+            Ok(())
+        });
+        assert_eq!(fathers.try_iter().count(), 0);
+        assert_eq!(people.try_iter().count(), 0);
+
+        // Removing a row reports the edges it loses.
+        db.remove_dog(mickey).unwrap();
+        assert_eq!(
+            dogs.try_iter().collect::<Vec<_>>(),
+            [EdgeChange::Removed(kid, mickey)]
+        );
+        assert_eq!(
+            people.try_iter().collect::<Vec<_>>(),
+            [Change::Updated(kid)]
+        );
+        db.remove_person(kid).unwrap();
+        assert_eq!(
+            people.try_iter().collect::<Vec<_>>(),
+            [Change::Removed(kid)]
+        );
+
+        assert!(db.unobserve(dogs_id));
+        assert!(!db.unobserve(dogs_id));
+        drop(db);
+        assert!(dogs.recv().is_err());
+    }
+    #[test]
+    fn caught_panic() {
+        use polygraph::Change;
+        use std::sync::mpsc::channel;
+
+        let mut db = Tree::new();
+        db.enable_history();
+        let (dogs_tx, dogs) = channel();
+        db.observe_dog(move |c| dogs_tx.send(*c).unwrap());
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let gone = db.insert_dog(Dog {
+            name: "Gone".to_string(),
+        });
+        db.remove_dog(gone).unwrap();
+        dogs.try_iter().count();
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.insert_person(Person {
+                last_name: roundy,
+                father: None,
+                mother: None,
+                name: "David".to_string(),
+                dog: [gone].iter().cloned().collect(),
+            });
+        }));
+        assert!(panicked.is_err());
+
+        // Later changes are still observed and can be undone.
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        assert_eq!(
+            dogs.try_iter().collect::<Vec<_>>(),
+            [Change::Inserted(mickey)]
+        );
+        assert!(db.undo());
+        assert!(db.get_dog(mickey).is_none());
+    }
+    #[test]
+    fn live() {
+        let mut db = Tree::new();
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let smith = db.insert_surname(Surname("Smith".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: KeySet::new(),
+        });
+        let roundy_dog_owners = db.live_person(|db, p| {
+            db[db[p].last_name].0.starts_with("Roundy") && !db[p].dog.is_empty()
+        });
+        let nicknamed = db.live_person(|db, p| {
+            db[p]
+                .person_of
+                .iter()
+                .any(|n| db[n].name.starts_with("The "))
+        });
+        assert!(db[roundy_dog_owners].is_empty());
+
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        db.add_person_dog(me, mickey);
+        assert_eq!(db[roundy_dog_owners].iter().collect::<Vec<_>>(), [me]);
+
+        let kid = db.insert_person(Person {
+            last_name: smith,
+            father: Some(me),
+            mother: None,
+            name: "Kid".to_string(),
+            dog: [mickey].iter().cloned().collect(),
+        });
+        assert_eq!(db[roundy_dog_owners].len(), 1);
+        // Renaming the surname a person refers to changes the result.
+        db.set_surname(smith, Surname("Roundy-Smith".to_string()))
+            .unwrap();
+        assert!(db[roundy_dog_owners].contains(kid));
+        db.set_person_last_name(kid, roundy);
+        db.remove_surname(smith).unwrap();
+        assert!(db[roundy_dog_owners].contains(kid));
+
+        // So does a change to a row that refers to the person.
+        let nickname = db.insert_nickname(Nickname {
+            person: kid,
+            name: "Kiddo".to_string(),
+        });
+        assert!(db[nicknamed].is_empty());
+        db.with_nickname_name(nickname, |n| *n = "The Kid".to_string());
+        assert_eq!(db[nicknamed].iter().collect::<Vec<_>>(), [kid]);
+
+        db.remove_dog(mickey).unwrap();
+        assert!(db[roundy_dog_owners].is_empty());
+        db.remove_person(kid).unwrap();
+        assert!(db[nicknamed].is_empty());
+
+        assert!(db.drop_live(nicknamed));
+        assert!(!db.drop_live(nicknamed));
+    }
+    #[test]
+    fn shared() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Tree>();
+
+        let mut db = Tree::new();
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: KeySet::new(),
+        });
+        let db = polygraph::Shared::new(db);
+
+        std::thread::scope(|s| {
+            for w in 0..4 {
+                let db = &db;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        let mut db = db.write();
+                        let dog = db.insert_dog(Dog {
+                            name: format!("Dog {} {}", w, i),
+                        });
+                        let kid = db.insert_person(Person {
+                            last_name: roundy,
+                            father: Some(me),
+                            mother: None,
+                            name: format!("Kid {} {}", w, i),
+                            dog: [dog].iter().cloned().collect(),
+                        });
+                        if i % 2 == 0 {
+                            db.remove_person(kid).unwrap();
+                        }
+                    }
+                });
+            }
+            for _ in 0..4 {
+                let db = &db;
+                s.spawn(move || {
+                    for _ in 0..200 {
+                        let db = db.read();
+                        let kids = &db[me].father_of;
+                        assert_eq!(kids.len() + 1, db.len_person());
+                        assert_eq!(db[roundy].last_name_of.len(), db.len_person());
+                        for kid in kids.iter() {
+                            assert_eq!(db[kid].father, Some(me));
+                            for dog in db[kid].dog.iter() {
+                                assert!(db[dog].dog_of.contains(kid));
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let db = db.into_inner();
+        assert_eq!(db.len_person(), 201);
+        assert_eq!(db.len_dog(), 400);
+        assert_eq!(db[me].father_of.len(), 200);
+    }
+    #[test]
+    fn versioned() {
+        let mut db = Tree::new();
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: KeySet::new(),
+        });
+        let db = polygraph::Versioned::new(db);
+
+        // A reader keeps its version however long it holds it, without
+        // holding up the writer.
+        let old = db.read();
+        let kid = db.write(|db| {
+            db.insert_person(Person {
+                last_name: roundy,
+                father: Some(me),
+                mother: None,
+                name: "Kid".to_string(),
+                dog: KeySet::new(),
+            })
+        });
+        assert_eq!(db.version(), 1);
+        assert!(old.get_person(kid).is_none());
+        assert!(old[me].father_of.is_empty());
+        let new = db.read();
+        assert_eq!(kid.d(&new).name, "Kid");
+        assert_eq!(
+            new.lookup_surname(&Surname("Roundy".to_string())),
+            Some(roundy)
+        );
+
+        std::thread::scope(|s| {
+            let db = &db;
+            s.spawn(move || {
+                for i in 0..200 {
+                    db.write(|db| {
+                        let dog = db.insert_dog(Dog {
+                            name: format!("Dog {}", i),
+                        });
+                        db.add_person_dog(me, dog);
+                        db.add_person_dog(kid, dog);
+                    });
+                }
+            });
+            for _ in 0..4 {
+                s.spawn(move || {
+                    let mut seen = 0;
+                    while seen < 200 {
+                        let view = db.read();
+                        assert_eq!(view[me].dog, view[kid].dog);
+                        assert_eq!(view[me].dog.len(), view.len_dog());
+                        for dog in view[me].dog.iter() {
+                            assert_eq!(view[dog].dog_of.len(), 2);
+                        }
+                        assert!(view.len_dog() >= seen);
+                        seen = view.len_dog();
+                    }
+                });
+            }
+        });
+        assert_eq!(db.version(), 201);
+        assert!(old[me].dog.is_empty());
+    }
+    #[test]
+    fn refs() {
+        let mut db = Tree::new();
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let grandpa = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "Grandpa".to_string(),
+            dog: KeySet::new(),
+        });
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: Some(grandpa),
+            mother: None,
+            name: "David".to_string(),
+            dog: [mickey].iter().cloned().collect(),
+        });
+        db.insert_nickname(Nickname {
+            person: me,
+            name: "Dave".to_string(),
+        });
+
+        let r = me.r(&db);
+        assert_eq!(r.name, "David");
+        assert_eq!(r.key(), me);
+        assert_eq!(r.last_name().0, "Roundy");
+        assert_eq!(r.father().unwrap().name, "Grandpa");
+        assert!(r.mother().is_none());
+        assert_eq!(
+            r.father().unwrap().father_of().keys(),
+            &grandpa.r(&db).father_of
+        );
+        assert!(r.father().unwrap().father_of().contains(me));
+        assert_eq!(
+            r.dog().iter().map(|d| d.name.clone()).collect::<Vec<_>>(),
+            ["Mickey"]
+        );
+        assert_eq!(mickey.r(&db).dog_of().iter().next(), Some(r));
+        assert_eq!(
+            r.person_of()
+                .iter()
+                .map(|n| n.name.clone())
+                .collect::<Vec<_>>(),
+            ["Dave"]
+        );
+        assert_eq!(r.last_name().last_name_of().len(), 2);
+        assert!(r.father_of().is_empty());
+    }
+    #[test]
+    fn iterate() {
+        let mut db = Tree::new();
+        assert!(db.is_empty_person());
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let people: Vec<_> = ["A", "B", "C"]
+            .iter()
+            .map(|name| {
+                db.insert_person(Person {
+                    last_name: roundy,
+                    father: None,
+                    mother: None,
+                    name: name.to_string(),
+                    dog: KeySet::new(),
+                })
+            })
+            .collect();
+        db.remove_person(people[1]).unwrap();
+
+        assert_eq!(db.len_person(), 2);
+        assert!(!db.is_empty_person());
+        assert_eq!(db.person_keys().collect::<Vec<_>>(), [people[0], people[2]]);
+        assert_eq!(
+            db.persons()
+                .map(|(k, p)| (k, p.name.as_str()))
+                .collect::<Vec<_>>(),
+            [(people[0], "A"), (people[2], "C")]
+        );
+        assert_eq!(
+            db.surnames().next().map(|(k, s)| (k, s.0.as_str())),
+            Some((roundy, "Roundy"))
+        );
+        assert_eq!(db.len_dog(), 0);
+        assert_eq!(db.dog_keys().count(), 0);
+    }
+    #[test]
+    fn try_mutators() {
+        use polygraph::Error;
+
+        let mut db = Tree::new();
+        let roundy = db.insert_surname(Surname("Roundy".to_string()));
+        let me = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "David".to_string(),
+            dog: KeySet::new(),
+        });
+        let gone = db.insert_person(Person {
+            last_name: roundy,
+            father: None,
+            mother: None,
+            name: "Gone".to_string(),
+            dog: KeySet::new(),
+        });
+        db.remove_person(gone).unwrap();
+        let stale = Err(Error::StaleKey { table: "Person" });
+        assert_eq!(db.remove_person(gone).err(), stale.err());
+        assert_eq!(db.try_set_person_father(me, Some(gone)), stale);
+        assert_eq!(db.try_set_person_father(gone, Some(me)), stale);
+        assert_eq!(db[me].father, None);
+        assert!(db.try_with_person_name(gone, |_| ()).is_err());
+        assert_eq!(db.try_merge_person(me, gone).err(), stale.err());
+        assert_eq!(
+            db.try_merge_person(me, me).err(),
+            Some(Error::SelfMerge { table: "Person" })
+        );
+        assert_eq!(db[me].name, "David");
+
+        // A key from a database with more rows refers past the end of
+        // this one.
+        let mut other = Tree::new();
+        let smiths: Vec<_> = (0..3)
+            .map(|i| other.insert_surname(Surname(format!("Smith {}", i))))
+            .collect();
+        let foreign = Person {
+            last_name: smiths[2],
+            father: None,
+            mother: None,
+            name: "Foreigner".to_string(),
+            dog: KeySet::new(),
+        };
+        assert_eq!(
+            db.try_insert_person(foreign.clone()).err(),
+            Some(Error::ForeignKey { table: "Surname" })
+        );
+        assert_eq!(db.len_person(), 1);
+        assert_eq!(
+            db.try_set_person(me, foreign).err(),
+            Some(Error::ForeignKey { table: "Surname" })
+        );
+        assert_eq!(
+            db.try_modify_person(me, |p| p.last_name = smiths[2]),
+            Err(Error::ForeignKey { table: "Surname" })
+        );
+        assert_eq!(db[me].last_name, roundy);
+
+        let mickey = db.insert_dog(Dog {
+            name: "Mickey".to_string(),
+        });
+        assert_eq!(db.try_add_person_dog(me, mickey), Ok(true));
+        assert_eq!(db.try_remove_person_dog(me, mickey), Ok(true));
+        assert_eq!(
+            db.try_modify_person(me, |p| {
+                p.father = Some(me);
+                "done"
+            }),
+            Ok("done")
+        );
+        assert!(db[me].father_of.contains(me));
+        let kid = db
+            .try_insert_person(Person {
+                last_name: roundy,
+                father: Some(me),
+                mother: None,
+                name: "Kid".to_string(),
+                dog: KeySet::new(),
+            })
+            .unwrap();
+        assert!(db[me].father_of.contains(kid));
+        assert_eq!(
+            db.remove_surname(roundy).err(),
+            Some(Error::Restricted {
+                table: "Person",
+                field: "last_name"
+            })
+        );
+    }
+    #[test]
+    fn branded() {
+        polygraph_macro::schema! {
+            /// A database whose keys do not work in any other.
+            #[polygraph(branded)]
+            type Branded;
+            pub struct Name(pub String);
+            pub struct Pet {
+                pub name: Key<Name>,
+            }
+        }
+        let mut a = Branded::new();
+        let mut b = Branded::new();
+        let fido = a.insert_name(Name("Fido".to_string()));
+        let rex = b.insert_name(Name("Rex".to_string()));
+        let pet = a.insert_pet(Pet { name: fido });
+        assert_eq!(a[pet].name, fido);
+        assert!(a.get_name(rex).is_none());
+        assert!(b.get_name(fido).is_none());
+        assert!(b.get_pet(pet).is_none());
+        assert_eq!(
+            b.try_insert_pet(Pet { name: fido }).err(),
+            Some(polygraph::Error::ForeignKey { table: "Name" })
+        );
+        let b = std::panic::AssertUnwindSafe(&b);
+        assert!(std::panic::catch_unwind(|| b[pet].name).is_err());
+
+        // Keys still work in snapshots, and after being undone and redone.
+        a.enable_history();
+        let spot = a.insert_name(Name("Spot".to_string()));
+        assert_eq!(a.snapshot()[spot].0, "Spot");
+        a.undo();
+        assert!(a.get_name(spot).is_none());
+        a.redo();
+        assert_eq!(a[spot].0, "Spot");
+        a.remove_pet(pet).unwrap();
+        assert_eq!(
+            a.try_set_pet(pet, Pet { name: spot }).err(),
+            Some(polygraph::Error::StaleKey { table: "Pet" })
+        );
+    }
+
+    #[test]
+    fn enum_tables() {
+        polygraph_macro::schema! {
+            type Events;
+            pub struct Person(pub String);
+            pub enum Weekday {
+                Monday,
+                Friday,
+            }
+            /// A table that can never have a row.
+            pub enum Never {}
+            pub enum Event {
+                Birth {
+                    child: Key<Person>,
+                    day: Key<Weekday>,
+                },
+                Marriage(
+                    #[polygraph(on_delete = "cascade")] Key<Person>,
+                    #[polygraph(on_delete = "cascade")] Key<Person>,
+                ),
+                Meeting {
+                    attendees: KeySet<Person>,
+                    chair: Option<Key<Person>>,
+                },
+                Party {
+                    attendees: KeySet<Person>,
+                },
+                Holiday,
+            }
+        }
+        let mut db = Events::new();
+        let alice = db.insert_person(Person("Alice".to_string()));
+        let bob = db.insert_person(Person("Bob".to_string()));
+        let carol = db.insert_person(Person("Carol".to_string()));
+        let friday = db.insert_weekday(Weekday::Friday);
+        assert_eq!(db.lookup_weekday(&Weekday::Friday), Some(friday));
+        assert_eq!(db.lookup_weekday(&Weekday::Monday), None);
+        assert!(db.is_empty_never());
+
+        let birth = db.insert_event(Event::Birth {
+            child: carol,
+            day: friday,
+        });
+        let wedding = db.insert_event(Event::Marriage(alice, bob));
+        let meeting = db.insert_event(Event::Meeting {
+            attendees: [alice, carol].iter().copied().collect(),
+            chair: Some(alice),
+        });
+        let party = db.insert_event(Event::Party {
+            attendees: [bob].iter().copied().collect(),
+        });
+        db.insert_event(Event::Holiday);
+        assert_eq!(db[carol].child_of.iter().collect::<Vec<_>>(), vec![birth]);
+        assert_eq!(db[friday].day_of.iter().collect::<Vec<_>>(), vec![birth]);
+        assert_eq!(
+            db[alice].marriage_0_of.iter().collect::<Vec<_>>(),
+            vec![wedding]
+        );
+        assert_eq!(
+            db[bob].marriage_1_of.iter().collect::<Vec<_>>(),
+            vec![wedding]
+        );
+        assert_eq!(db[alice].chair_of.iter().collect::<Vec<_>>(), vec![meeting]);
+        // Both variants with an `attendees` field share one reverse set.
+        assert_eq!(db[bob].attendees_of.iter().collect::<Vec<_>>(), vec![party]);
+        assert_eq!(
+            db[carol].attendees_of.iter().collect::<Vec<_>>(),
+            vec![meeting]
+        );
+        assert_eq!(
+            carol
+                .r(&db)
+                .child_of()
+                .iter()
+                .map(|r| r.key())
+                .collect::<Vec<_>>(),
+            vec![birth]
+        );
+        assert_eq!(birth.r(&db).child().map(|r| r.key()), Some(carol));
+        assert_eq!(party.r(&db).child().map(|r| r.key()), None);
+        assert_eq!(
+            party
+                .r(&db)
+                .attendees()
+                .map(|r| r.key())
+                .collect::<Vec<_>>(),
+            vec![bob]
+        );
+
+        // Changing the variant of a row moves its references.
+        db.modify_event(party, |e| {
+            *e = Event::Meeting {
+                attendees: [carol].iter().copied().collect(),
+                chair: Some(bob),
+            }
+        });
+        assert!(db[bob].attendees_of.is_empty());
+        assert_eq!(db[bob].chair_of.iter().collect::<Vec<_>>(), vec![party]);
+        assert_eq!(db[carol].attendees_of.len(), 2);
+
+        // A birth restricts removing the child, attendees are dropped, and
+        // a marriage goes away with either spouse.
+        assert_eq!(
+            db.remove_person(carol).err(),
+            Some(polygraph::Error::Restricted {
+                table: "Event",
+                field: "child",
+            })
+        );
+        db.remove_event(birth).unwrap();
+        db.remove_person(carol).unwrap();
+        assert_eq!(
+            db[alice].attendees_of.iter().collect::<Vec<_>>(),
+            vec![meeting]
+        );
+        match &*db[meeting] {
+            Event::Meeting { attendees, .. } => {
+                assert_eq!(attendees.iter().collect::<Vec<_>>(), vec![alice])
+            }
+            _ => panic!("the meeting should still be a meeting"),
+        }
+        db.remove_person(bob).unwrap();
+        assert!(db.get_event(wedding).is_none());
+        assert!(db[alice].marriage_0_of.is_empty());
+        match &*db[party] {
+            Event::Meeting { chair, .. } => assert_eq!(*chair, None),
+            _ => panic!("the party should have become a meeting"),
+        }
+        assert!(db.try_insert_event(Event::Marriage(alice, bob)).is_err());
+    }
+
+    #[test]
+    fn tuple_tables() {
+        polygraph_macro::schema! {
+            type Graph;
+            pub struct Node(pub String);
+            pub struct Edge(
+                #[polygraph(on_delete = "cascade")] pub Key<Node>,
+                #[polygraph(on_delete = "cascade")] pub Key<Node>,
+                pub f64,
+            );
+            pub struct Label(#[polygraph(name = "labelled")] pub KeySet<Node>, pub String);
+        }
+        let mut db = Graph::new();
+        let a = db.insert_node(Node("a".to_string()));
+        let b = db.insert_node(Node("b".to_string()));
+        let c = db.insert_node(Node("c".to_string()));
+        let ab = db.insert_edge(Edge(a, b, 1.0));
+        let bc = db.insert_edge(Edge(b, c, 2.0));
+        let label = db.insert_label(Label([a, c].iter().copied().collect(), "ends".to_string()));
+        assert_eq!(db[a].edge_0_of.iter().collect::<Vec<_>>(), vec![ab]);
+        assert_eq!(db[b].edge_1_of.iter().collect::<Vec<_>>(), vec![ab]);
+        assert_eq!(db[b].edge_0_of.iter().collect::<Vec<_>>(), vec![bc]);
+        assert_eq!(db[c].labelled_of.iter().collect::<Vec<_>>(), vec![label]);
+        assert_eq!(db[label].1, "ends");
+        assert_eq!(ab.r(&db).edge_1().key(), b);
+        assert_eq!(
+            b.r(&db)
+                .edge_1_of()
+                .iter()
+                .map(|e| e.edge_0().key())
+                .collect::<Vec<_>>(),
+            vec![a]
+        );
+
+        db.with_edge_2(ab, |w| *w += 1.0);
+        assert_eq!(db[ab].2, 2.0);
+        db.set_edge_edge_1(ab, c);
+        assert!(db[b].edge_1_of.is_empty());
+        assert_eq!(db[c].edge_1_of.len(), 2);
+        db.remove_label_labelled(label, a);
+        assert!(db[a].labelled_of.is_empty());
+
+        db.remove_node(b).unwrap();
+        assert!(db.get_edge(bc).is_none());
+        assert_eq!(db[c].edge_1_of.iter().collect::<Vec<_>>(), vec![ab]);
+    }
+
+    #[test]
+    fn ordered_relationships() {
+        polygraph_macro::schema! {
+            type Music;
+            pub struct Track(pub String);
+            pub struct Playlist {
+                pub name: String,
+                pub tracks: Vec<Key<Track>>,
+            }
+        }
+        let mut db = Music::new();
+        let one = db.insert_track(Track("one".to_string()));
+        let two = db.insert_track(Track("two".to_string()));
+        let three = db.insert_track(Track("three".to_string()));
+        let mix = db.insert_playlist(Playlist {
+            name: "mix".to_string(),
+            tracks: vec![one, two, one],
+        });
+        assert_eq!(db[one].tracks_of.iter().collect::<Vec<_>>(), vec![mix]);
+        assert_eq!(db[mix].name, "mix");
+        assert_eq!(
+            mix.r(&db).tracks().map(|t| t.0.clone()).collect::<Vec<_>>(),
+            vec!["one", "two", "one"]
+        );
+
+        db.push_playlist_tracks(mix, three);
+        db.insert_playlist_tracks_at(mix, 0, three);
+        assert_eq!(db[mix].tracks, vec![three, one, two, one, three]);
+        assert_eq!(db[three].tracks_of.iter().collect::<Vec<_>>(), vec![mix]);
+        // A row stays in the reverse set until its last occurrence goes.
+        assert_eq!(db.remove_playlist_tracks_at(mix, 1), one);
+        assert_eq!(db[one].tracks_of.iter().collect::<Vec<_>>(), vec![mix]);
+        assert_eq!(db.remove_playlist_tracks_at(mix, 2), one);
+        assert!(db[one].tracks_of.is_empty());
+        assert_eq!(db[mix].tracks, vec![three, two, three]);
+
+        // Removing a track drops every occurrence of it.
+        db.remove_track(three).unwrap();
+        assert_eq!(db[mix].tracks, vec![two]);
+
+        // Merging tracks keeps the order of the list.
+        db.push_playlist_tracks(mix, one);
+        db.merge_track(one, two);
+        assert_eq!(db[mix].tracks, vec![one, one]);
+        assert_eq!(db[one].tracks_of.iter().collect::<Vec<_>>(), vec![mix]);
+        assert!(db.try_push_playlist_tracks(mix, two).is_err());
+
+        // An index past the end of the list is an error.
+        let past_end = |index| polygraph::Error::OutOfBounds {
+            table: "Playlist",
+            field: "tracks",
+            index,
+            len: 2,
+        };
+        assert_eq!(
+            db.try_insert_playlist_tracks_at(mix, 3, one),
+            Err(past_end(3))
+        );
+        assert_eq!(db.try_remove_playlist_tracks_at(mix, 2), Err(past_end(2)));
+        assert_eq!(db.try_insert_playlist_tracks_at(mix, 2, one), Ok(()));
+        assert_eq!(db.try_remove_playlist_tracks_at(mix, 2), Ok(one));
+        assert_eq!(db[mix].tracks, vec![one, one]);
+    }
+
+    #[test]
+    fn edge_payloads() {
+        #[derive(Clone, Debug, PartialEq)]
+        pub struct Adoption {
+            pub year: u32,
+        }
+        polygraph_macro::schema! {
+            type Kennel;
+            pub struct Dog(pub String);
+            pub struct Owner {
+                pub name: String,
+                pub dog: KeyMap<Dog, Adoption>,
+            }
+        }
+        let mut db = Kennel::new();
+        db.enable_history();
+        let rex = db.insert_dog(Dog("Rex".to_string()));
+        let spot = db.insert_dog(Dog("Spot".to_string()));
+        let mut dogs = std::collections::HashMap::new();
+        dogs.insert(rex, Adoption { year: 2010 });
+        let ann = db.insert_owner(Owner {
+            name: "Ann".to_string(),
+            dog: dogs,
+        });
+        let bo = db.insert_owner(Owner {
+            name: "Bo".to_string(),
+            dog: Default::default(),
+        });
+        assert_eq!(db.add_owner_dog(bo, rex, Adoption { year: 2015 }), None);
+        assert_eq!(db.add_owner_dog(bo, spot, Adoption { year: 2012 }), None);
+        assert_eq!(db[rex].dog_of.len(), 2);
+        let mut adoptions: Vec<_> = rex
+            .r(&db)
+            .dog_of_payloads()
+            .map(|(o, a)| (o.name.clone(), a.year))
+            .collect();
+        adoptions.sort();
+        assert_eq!(
+            adoptions,
+            vec![("Ann".to_string(), 2010), ("Bo".to_string(), 2015)]
+        );
+        assert_eq!(
+            ann.r(&db)
+                .dog()
+                .map(|(d, a)| (d.key(), a.year))
+                .collect::<Vec<_>>(),
+            vec![(rex, 2010)]
+        );
+
+        // Payloads can be changed without touching the reverse sets, and
+        // the change can be undone.
+        assert_eq!(db.with_owner_dog(ann, rex, |a| a.year = 2011), Some(()));
+        assert_eq!(db[ann].dog[&rex].year, 2011);
+        assert_eq!(db.with_owner_dog(ann, spot, |a| a.year = 2011), None);
+        db.undo();
+        assert_eq!(db[ann].dog[&rex].year, 2010);
+
+        assert_eq!(db.remove_owner_dog(bo, rex), Some(Adoption { year: 2015 }));
+        assert_eq!(db[rex].dog_of.iter().collect::<Vec<_>>(), vec![ann]);
+
+        // Merging keeps the payload of the dog being kept.
+        db.add_owner_dog(ann, spot, Adoption { year: 2020 });
+        db.merge_dog(spot, rex);
+        assert_eq!(db[ann].dog.len(), 1);
+        assert_eq!(db[ann].dog[&spot].year, 2020);
+        assert_eq!(db[spot].dog_of.len(), 2);
+
+        db.remove_dog(spot).unwrap();
+        assert!(db[ann].dog.is_empty());
+        assert!(db[bo].dog.is_empty());
     }
 }
 
@@ -0,0 +1,95 @@
+//! Undo and redo.
+
+/// The undo and redo stacks of a database, holding the groups of changes
+/// made by each call that modified it.
+#[derive(Clone, Debug)]
+pub struct History<C> {
+    undo: Vec<C>,
+    redo: Vec<C>,
+    checkpoints: Vec<(String, usize)>,
+    enabled: bool,
+    recording: bool,
+}
+
+impl<C> Default for History<C> {
+    fn default() -> Self {
+        History {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            checkpoints: Vec::new(),
+            enabled: false,
+            recording: false,
+        }
+    }
+}
+
+impl<C> History<C> {
+    /// Create a history that does not record anything until enabled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Start recording changes.
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+    /// Whether changes are being kept so they can be undone.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+    /// Note that a modification is starting.  Returns `true` if its
+    /// changes should be recorded as a new step, which is the case if
+    /// history is enabled or the changes are `observed`, unless some
+    /// enclosing modification is already being recorded.
+    pub fn enter(&mut self, observed: bool) -> bool {
+        if (self.enabled || observed) && !self.recording {
+            self.recording = true;
+            true
+        } else {
+            false
+        }
+    }
+    /// Note that the modification for which [`History::enter`] returned
+    /// `step` has finished.
+    pub fn exit(&mut self, step: bool) {
+        if step {
+            self.recording = false;
+        }
+    }
+    /// Whether a step is being recorded.
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+    /// Record the changes of a new step.  This forgets anything that had
+    /// been undone, along with checkpoints that only it reached.
+    pub fn push(&mut self, changes: C) {
+        self.redo.clear();
+        let n = self.undo.len();
+        self.checkpoints.retain(|&(_, at)| at <= n);
+        self.undo.push(changes);
+    }
+    /// Take the most recent step to undo it.
+    pub fn pop_undo(&mut self) -> Option<C> {
+        self.undo.pop()
+    }
+    /// Record the changes that would redo a step just undone.
+    pub fn push_redo(&mut self, changes: C) {
+        self.redo.push(changes);
+    }
+    /// Take the most recently undone step to redo it.
+    pub fn pop_redo(&mut self) -> Option<C> {
+        self.redo.pop()
+    }
+    /// Record the changes that would undo a step just redone.
+    pub fn push_undo(&mut self, changes: C) {
+        self.undo.push(changes);
+    }
+    /// Name the current state, so it can be returned to.
+    pub fn checkpoint(&mut self, name: &str) {
+        self.checkpoints.push((name.to_string(), self.undo.len()));
+    }
+    /// How many steps must be undone to get back to the named checkpoint.
+    pub fn steps_since(&self, name: &str) -> Option<usize> {
+        let &(_, at) = self.checkpoints.iter().rev().find(|(n, _)| n == name)?;
+        self.undo.len().checked_sub(at)
+    }
+}
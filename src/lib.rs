@@ -1,5 +1,36 @@
 pub use polygraph_macro::schema;
 
+// This lets the code generated by `schema!` refer to `polygraph::` even
+// within this crate.
+extern crate self as polygraph;
+
+mod error;
+pub use error::Error;
+
+mod history;
+pub use history::History;
+
+mod live;
+pub use live::{Live, LiveQueries};
+
+mod lookup;
+pub use lookup::{Lookup, LookupChanges};
+
+mod observe;
+pub use observe::{Change, EdgeChange, ObserverId, Observers};
+
+mod shared;
+pub use shared::Shared;
+
+mod snapshot;
+pub use snapshot::Snapshot;
+
+mod table;
+pub use table::{new_brand, RawKey, Table, TableChanges};
+
+mod versioned;
+pub use versioned::{Database, Versioned};
+
 pub mod example;
 
 pub mod new;
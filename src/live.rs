@@ -0,0 +1,117 @@
+//! Queries whose results are kept up to date as the database changes.
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tinyset::{Fits64, Set64};
+
+/// A handle to a live query, which gives its current result when used as
+/// an index into the database.
+pub struct Live<K> {
+    id: u64,
+    _key: PhantomData<K>,
+}
+
+impl<K> Copy for Live<K> {}
+impl<K> Clone for Live<K> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<K> PartialEq for Live<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+impl<K> Eq for Live<K> {}
+impl<K> std::fmt::Debug for Live<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Live({})", self.id)
+    }
+}
+
+type Predicate<D, K> = Box<dyn Fn(&D, K) -> bool + Send + Sync>;
+
+struct Query<D, K: Fits64> {
+    id: u64,
+    predicate: Predicate<D, K>,
+    result: Set64<K>,
+}
+
+/// The live queries over one table of a database `D`, whose rows have
+/// keys `K`.
+pub struct LiveQueries<D, K: Fits64> {
+    queries: Vec<Query<D, K>>,
+}
+
+impl<D, K: Fits64> Default for LiveQueries<D, K> {
+    fn default() -> Self {
+        LiveQueries {
+            queries: Vec::new(),
+        }
+    }
+}
+
+impl<D, K: Fits64> std::fmt::Debug for LiveQueries<D, K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "LiveQueries({})", self.queries.len())
+    }
+}
+
+impl<D, K: Fits64 + Copy> LiveQueries<D, K> {
+    /// Whether there are no queries, in which case there is no need to
+    /// work out which rows changed.
+    pub fn is_empty(&self) -> bool {
+        self.queries.is_empty()
+    }
+    /// Add a query, finding its result among `rows`, which should be
+    /// every row of the table.
+    pub fn add(
+        &mut self,
+        db: &D,
+        rows: impl Iterator<Item = K>,
+        predicate: impl Fn(&D, K) -> bool + Send + Sync + 'static,
+    ) -> Live<K> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT.fetch_add(1, Ordering::Relaxed);
+        let result = rows.filter(|&k| predicate(db, k)).collect();
+        self.queries.push(Query {
+            id,
+            predicate: Box::new(predicate),
+            result,
+        });
+        Live {
+            id,
+            _key: PhantomData,
+        }
+    }
+    /// Remove a query, returning `false` if it is not one of these.
+    pub fn remove<J>(&mut self, live: Live<J>) -> bool {
+        let len = self.queries.len();
+        self.queries.retain(|q| q.id != live.id);
+        self.queries.len() != len
+    }
+    /// The current result of a query, if it is one of these.
+    pub fn result(&self, live: Live<K>) -> Option<&Set64<K>> {
+        self.queries
+            .iter()
+            .find(|q| q.id == live.id)
+            .map(|q| &q.result)
+    }
+    /// Bring every result up to date, given the rows that may have
+    /// changed and those that have been removed.
+    pub fn update(&mut self, db: &D, changed: &[K], removed: &[K]) {
+        for q in self.queries.iter_mut() {
+            for &k in removed {
+                q.result.remove(&k);
+            }
+            for &k in changed {
+                if (q.predicate)(db, k) {
+                    q.result.insert(k);
+                } else {
+                    q.result.remove(&k);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Hashes for finding rows by value.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use crate::RawKey;
+
+/// A hash from values to the keys of the rows that hold them.
+///
+/// Like a [`Table`](crate::Table), it records changes so they can be
+/// undone, and shares its storage with its snapshots.
+#[derive(Clone, Debug)]
+pub struct Lookup<K> {
+    map: Trie<K>,
+    journal: Vec<LookupChanges<K>>,
+}
+
+/// The state of a [`Lookup`] before a group of changes, which
+/// `revert` can restore.
+#[derive(Clone, Debug)]
+pub struct LookupChanges<K> {
+    /// The old value for each entry that changed.
+    entries: HashMap<K, Option<RawKey>>,
+}
+
+impl<K> LookupChanges<K> {
+    /// Whether there are no changes.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K> Default for Lookup<K> {
+    fn default() -> Self {
+        Lookup {
+            map: Trie::default(),
+            journal: Vec::new(),
+        }
+    }
+}
+
+impl<K> Lookup<K> {
+    /// A copy of the lookup as it is now, sharing its storage, as with
+    /// [`Table::snapshot`](crate::Table::snapshot).
+    pub fn snapshot(&self) -> Self {
+        Lookup {
+            map: self.map.clone(),
+            journal: Vec::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq> Lookup<K> {
+    /// Create an empty lookup.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The key of the row holding `value`, if any.
+    pub fn get(&self, value: &K) -> Option<&RawKey> {
+        self.map.get(value)
+    }
+    /// The number of values with a row recorded.
+    pub fn len(&self) -> usize {
+        self.map.len
+    }
+    /// Whether no value has a row recorded.
+    pub fn is_empty(&self) -> bool {
+        self.map.len == 0
+    }
+}
+
+impl<K: Hash + Eq + Clone> Lookup<K> {
+    /// Record that `value` is held by the row at `key`, returning the key
+    /// previously recorded.
+    pub fn insert(&mut self, value: K, key: RawKey) -> Option<RawKey> {
+        self.touch(&value);
+        self.map.insert(value, key)
+    }
+    /// Forget which row holds `value`.
+    pub fn remove(&mut self, value: &K) -> Option<RawKey> {
+        self.touch(value);
+        self.map.remove(value)
+    }
+
+    /// Start recording changes.  Groups of changes may be nested.
+    #[doc(hidden)]
+    pub fn begin(&mut self) {
+        self.journal.push(LookupChanges {
+            entries: HashMap::new(),
+        });
+    }
+    /// Stop recording the current group of changes, folding it into the
+    /// enclosing group if there is one.
+    #[doc(hidden)]
+    pub fn commit(&mut self) {
+        let changes = self.end();
+        if let Some(parent) = self.journal.last_mut() {
+            for (value, key) in changes.entries {
+                parent.entries.entry(value).or_insert(key);
+            }
+        }
+    }
+    /// Stop recording the current group of changes and return it.
+    #[doc(hidden)]
+    pub fn end(&mut self) -> LookupChanges<K> {
+        self.journal.pop().expect("end without begin")
+    }
+    /// Undo a group of changes, returning the changes that would redo it.
+    #[doc(hidden)]
+    pub fn revert(&mut self, changes: LookupChanges<K>) -> LookupChanges<K> {
+        // Each entry goes back to how it was before the changes, so an
+        // enclosing group has nothing to record.
+        let mut inverse = HashMap::with_capacity(changes.entries.len());
+        for (value, key) in changes.entries {
+            let current = match key {
+                Some(key) => self.map.insert(value.clone(), key),
+                None => self.map.remove(&value),
+            };
+            inverse.insert(value, current);
+        }
+        LookupChanges { entries: inverse }
+    }
+
+    fn touch(&mut self, value: &K) {
+        if let Some(changes) = self.journal.last_mut() {
+            if !changes.entries.contains_key(value) {
+                let old = self.map.get(value).copied();
+                changes.entries.insert(value.clone(), old);
+            }
+        }
+    }
+}
+
+/// The number of bits of the hash used at each level of a [`Trie`].
+const BITS: u32 = 4;
+const MASK: u64 = (1 << BITS) - 1;
+/// The most entries a leaf holds before it is split.
+const LEAF: usize = 8;
+
+/// A hash trie whose clones share every node that neither has modified.
+/// Each modification copies only the path from the root to one leaf.
+struct Trie<K> {
+    root: Arc<Node<K>>,
+    hasher: RandomState,
+    len: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Node<K> {
+    Leaf(Vec<(u64, K, RawKey)>),
+    Branch(Vec<Option<Arc<Node<K>>>>),
+}
+
+impl<K> Clone for Trie<K> {
+    fn clone(&self) -> Self {
+        Trie {
+            root: self.root.clone(),
+            hasher: self.hasher.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<K: std::fmt::Debug> std::fmt::Debug for Trie<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.root.fmt(f)
+    }
+}
+
+impl<K> Default for Trie<K> {
+    fn default() -> Self {
+        Trie {
+            root: Arc::new(Node::Leaf(Vec::new())),
+            hasher: RandomState::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq> Trie<K> {
+    fn hash(&self, value: &K) -> u64 {
+        self.hasher.hash_one(value)
+    }
+    fn get(&self, value: &K) -> Option<&RawKey> {
+        let hash = self.hash(value);
+        let mut node = &*self.root;
+        let mut shift = 0;
+        loop {
+            match node {
+                Node::Leaf(entries) => {
+                    return entries
+                        .iter()
+                        .find(|(h, v, _)| *h == hash && v == value)
+                        .map(|(_, _, k)| k);
+                }
+                Node::Branch(children) => {
+                    node = children[(hash >> shift & MASK) as usize].as_deref()?;
+                    shift += BITS;
+                }
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Trie<K> {
+    fn insert(&mut self, value: K, key: RawKey) -> Option<RawKey> {
+        let hash = self.hash(&value);
+        let old = Self::insert_at(&mut self.root, hash, value, key, 0);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+    fn insert_at(
+        node: &mut Arc<Node<K>>,
+        hash: u64,
+        value: K,
+        key: RawKey,
+        shift: u32,
+    ) -> Option<RawKey> {
+        let node = Arc::make_mut(node);
+        let entries = match node {
+            Node::Branch(children) => {
+                let child = children[(hash >> shift & MASK) as usize]
+                    .get_or_insert_with(|| Arc::new(Node::Leaf(Vec::new())));
+                return Self::insert_at(child, hash, value, key, shift + BITS);
+            }
+            Node::Leaf(entries) => entries,
+        };
+        if let Some(e) = entries
+            .iter_mut()
+            .find(|(h, v, _)| *h == hash && *v == value)
+        {
+            return Some(std::mem::replace(&mut e.2, key));
+        }
+        entries.push((hash, value, key));
+        // Once every bit of the hash is used, colliding values must share
+        // a leaf however many there are.
+        if entries.len() > LEAF && shift < 64 {
+            let entries = std::mem::take(entries);
+            let mut branch = Arc::new(Node::Branch(vec![None; 1 << BITS]));
+            for (h, v, k) in entries {
+                Self::insert_at(&mut branch, h, v, k, shift);
+            }
+            *node = Arc::try_unwrap(branch).ok().unwrap();
+        }
+        None
+    }
+    fn remove(&mut self, value: &K) -> Option<RawKey> {
+        // Check first, so as not to copy any nodes if there is nothing to
+        // remove.
+        self.get(value)?;
+        let hash = self.hash(value);
+        self.len -= 1;
+        Self::remove_at(&mut self.root, hash, value, 0)
+    }
+    fn remove_at(node: &mut Arc<Node<K>>, hash: u64, value: &K, shift: u32) -> Option<RawKey> {
+        match Arc::make_mut(node) {
+            Node::Leaf(entries) => {
+                let i = entries
+                    .iter()
+                    .position(|(h, v, _)| *h == hash && v == value)?;
+                Some(entries.swap_remove(i).2)
+            }
+            Node::Branch(children) => {
+                let child = &mut children[(hash >> shift & MASK) as usize];
+                let removed = Self::remove_at(child.as_mut()?, hash, value, shift + BITS);
+                if let Some(Node::Leaf(entries)) = child.as_deref() {
+                    if entries.is_empty() {
+                        *child = None;
+                    }
+                }
+                removed
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Callbacks that are told about changes to a database.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A change to a row of a table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Change<K> {
+    /// A row was added.
+    Inserted(K),
+    /// A row was modified, which includes gaining or losing a back
+    /// reference.
+    Updated(K),
+    /// A row was removed.
+    Removed(K),
+}
+
+/// A change to a relationship, which is an edge from a row holding a key
+/// to the row it refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EdgeChange<F, T> {
+    /// The row `F` now refers to `T`.
+    Added(F, T),
+    /// The row `F` no longer refers to `T`.
+    Removed(F, T),
+}
+
+/// Identifies an observer, so that it can later be removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObserverId(u64);
+
+impl ObserverId {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ObserverId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A callback, in a `Mutex` so that a database holding it can be shared
+/// between threads even though the callback need not be `Sync`.
+type Callback<E> = Mutex<Box<dyn FnMut(&E) + Send>>;
+
+/// The callbacks observing one kind of change.
+///
+/// Callbacks must be `Send` so that a database holding them can be sent
+/// between threads.  They are only ever called with the database
+/// borrowed mutably, so the `Mutex` is never contended.
+pub struct Observers<E> {
+    callbacks: Vec<(ObserverId, Callback<E>)>,
+}
+
+impl<E> Default for Observers<E> {
+    fn default() -> Self {
+        Observers {
+            callbacks: Vec::new(),
+        }
+    }
+}
+
+impl<E> std::fmt::Debug for Observers<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Observers({})", self.callbacks.len())
+    }
+}
+
+impl<E> Observers<E> {
+    /// Whether there are no callbacks, in which case there is no need to
+    /// work out what changed.
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+    /// Add a callback.
+    pub fn add(&mut self, f: impl FnMut(&E) + Send + 'static) -> ObserverId {
+        let id = ObserverId::new();
+        self.callbacks.push((id, Mutex::new(Box::new(f))));
+        id
+    }
+    /// Remove a callback, returning `false` if it is not one of these.
+    pub fn remove(&mut self, id: ObserverId) -> bool {
+        let len = self.callbacks.len();
+        self.callbacks.retain(|(i, _)| *i != id);
+        self.callbacks.len() != len
+    }
+    /// Call every callback with `event`.
+    pub fn notify(&mut self, event: &E) {
+        for (_, f) in self.callbacks.iter_mut() {
+            let f = f.get_mut().unwrap_or_else(|e| e.into_inner());
+            f(event);
+        }
+    }
+}
+
+impl<K: Copy + PartialEq> Observers<Change<K>> {
+    /// Report a change to a slot, given the key of the row it held before
+    /// and after.
+    pub fn notify_row(&mut self, old: Option<K>, new: Option<K>) {
+        match (old, new) {
+            (Some(old), Some(new)) if old == new => self.notify(&Change::Updated(new)),
+            (old, new) => {
+                if let Some(old) = old {
+                    self.notify(&Change::Removed(old));
+                }
+                if let Some(new) = new {
+                    self.notify(&Change::Inserted(new));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! A database shared between threads.
+
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A database that many threads may read at once, while writes are made
+/// one at a time.
+///
+/// A read guard dereferences to the database, so rows can be found by
+/// indexing it with their keys.  No write can happen while any read guard
+/// is held, so everything read through one guard is consistent.
+///
+/// A thread that panics while holding the write guard may leave the
+/// database half modified, so every later attempt to use it panics too.
+/// Make changes within a `transaction` to have them undone instead.
+#[derive(Debug, Default)]
+pub struct Shared<D> {
+    db: RwLock<D>,
+}
+
+impl<D> Shared<D> {
+    /// Share a database.
+    pub fn new(db: D) -> Self {
+        Shared {
+            db: RwLock::new(db),
+        }
+    }
+    /// Wait until no thread is writing, and then read the database.
+    pub fn read(&self) -> RwLockReadGuard<'_, D> {
+        self.db.read().expect("a writer panicked")
+    }
+    /// Wait until no other thread is reading or writing, and then modify
+    /// the database.
+    pub fn write(&self) -> RwLockWriteGuard<'_, D> {
+        self.db.write().expect("a writer panicked")
+    }
+    /// Modify the database with `f`, returning its result.
+    pub fn update<R>(&self, f: impl FnOnce(&mut D) -> R) -> R {
+        f(&mut self.write())
+    }
+    /// Stop sharing the database.
+    pub fn into_inner(self) -> D {
+        self.db.into_inner().expect("a writer panicked")
+    }
+}
@@ -0,0 +1,30 @@
+//! Immutable views of a database.
+
+use std::sync::Arc;
+
+/// A database as it was when the snapshot was taken.  Later changes to
+/// the database do not affect it.
+///
+/// A snapshot dereferences to the database, so every method that only
+/// reads the database may be used on it.  Cloning a snapshot is cheap.
+pub struct Snapshot<D>(Arc<D>);
+
+impl<D> Snapshot<D> {
+    /// Wrap a database that nothing will modify again.
+    pub fn new(db: D) -> Self {
+        Snapshot(Arc::new(db))
+    }
+}
+
+impl<D> Clone for Snapshot<D> {
+    fn clone(&self) -> Self {
+        Snapshot(self.0.clone())
+    }
+}
+
+impl<D> std::ops::Deref for Snapshot<D> {
+    type Target = D;
+    fn deref(&self) -> &D {
+        &self.0
+    }
+}
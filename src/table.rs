@@ -0,0 +1,445 @@
+//! Storage for the rows of a single table.
+
+use std::sync::Arc;
+
+/// The untyped part of a key: which slot of a table the row lives in, and
+/// which generation of that slot it is.
+///
+/// A slot's generation changes whenever its row is removed, so a key for a
+/// removed row will never find the row that later reuses the slot.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RawKey {
+    index: u32,
+    generation: u32,
+}
+
+impl RawKey {
+    /// The slot this key refers to.
+    pub fn index(self) -> usize {
+        self.index as usize
+    }
+    /// The generation of the slot this key refers to.
+    pub fn generation(self) -> u32 {
+        self.generation
+    }
+}
+
+impl std::fmt::Debug for RawKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+impl tinyset::Fits64 for RawKey {
+    unsafe fn from_u64(x: u64) -> Self {
+        RawKey {
+            index: x as u32,
+            generation: (x >> 32) as u32,
+        }
+    }
+    fn to_u64(self) -> u64 {
+        // Keeping the generation in the high bits means that keys for
+        // slots that have never been reused are small numbers, which
+        // tinyset stores compactly.
+        (self.generation as u64) << 32 | self.index as u64
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Slot<T> {
+    /// The generation of the row, or for a vacant slot the generation the
+    /// next row will get.
+    generation: u32,
+    /// The earliest generation that has never been given to a row in this
+    /// slot.  Unlike `generation`, this survives reverting changes, so
+    /// that a key handed out by changes that were later reverted is never
+    /// handed out again.
+    fresh: u32,
+    row: Option<T>,
+    /// For a vacant slot, the next slot in the free list.
+    next_free: Option<u32>,
+}
+
+impl<T> Slot<T> {
+    fn vacant(brand: u32) -> Self {
+        Slot {
+            generation: brand,
+            fresh: brand,
+            row: None,
+            next_free: None,
+        }
+    }
+}
+
+/// A brand for [`Table::branded`] that is unlikely to be close to any
+/// other.
+pub fn new_brand() -> u32 {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish() as u32
+}
+
+/// The number of slots in each chunk of a [`Table`].
+const CHUNK: usize = 64;
+
+/// The rows of one table in a schema.
+///
+/// Rows never move once inserted, so that the slot of a row can serve as
+/// its key.  Removing a row frees its slot for reuse by a later row, with
+/// a new generation.
+///
+/// Changes made between `begin` and `end` are recorded, so that they can
+/// be undone with `revert`.  Those methods are hidden, since only the code
+/// `schema!` generates should call them, keeping them balanced across
+/// every table of a database.
+///
+/// The slots are stored in chunks shared between clones, so that
+/// [`Table::snapshot`] is cheap.  A chunk is copied the first time it is
+/// modified while shared.
+///
+/// A table may be branded, so that its generations start from some
+/// arbitrary number rather than zero.  A key from a table with a different
+/// brand will then almost certainly not find a row.
+#[derive(Clone, Debug)]
+pub struct Table<T> {
+    chunks: Arc<Vec<Arc<Vec<Slot<T>>>>>,
+    brand: u32,
+    nslots: usize,
+    free: Option<u32>,
+    len: usize,
+    journal: Vec<TableChanges<T>>,
+}
+
+/// The state of a [`Table`] before a group of changes, which
+/// `revert` can restore.
+#[derive(Clone, Debug)]
+pub struct TableChanges<T> {
+    /// The old contents of each slot that changed, or `None` for slots
+    /// that did not exist yet.
+    slots: Vec<(u32, Option<Slot<T>>)>,
+    touched: std::collections::HashSet<u32>,
+    nslots: usize,
+    free: Option<u32>,
+    len: usize,
+}
+
+impl<T> TableChanges<T> {
+    /// Whether there are no changes.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+}
+
+impl<T> Default for Table<T> {
+    fn default() -> Self {
+        Table {
+            chunks: Arc::new(Vec::new()),
+            brand: 0,
+            nslots: 0,
+            free: None,
+            len: 0,
+            journal: Vec::new(),
+        }
+    }
+}
+
+impl<T> Table<T> {
+    /// Create an empty table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Create an empty table with the given brand, which should be
+    /// shared by every table of one database and come from
+    /// [`new_brand`].
+    pub fn branded(brand: u32) -> Self {
+        Table {
+            brand,
+            ..Self::default()
+        }
+    }
+    /// A copy of the table as it is now, sharing its storage.  Unlike
+    /// `clone`, this takes constant time and does not copy any changes
+    /// being recorded.
+    pub fn snapshot(&self) -> Self {
+        Table {
+            chunks: self.chunks.clone(),
+            brand: self.brand,
+            nslots: self.nslots,
+            free: self.free,
+            len: self.len,
+            journal: Vec::new(),
+        }
+    }
+    /// The number of rows in the table.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    /// Whether the table has no rows.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// The key the next row to be inserted will get.
+    pub fn next_key(&self) -> RawKey {
+        if let Some(index) = self.free {
+            RawKey {
+                index,
+                generation: self.slot(index as usize).unwrap().generation,
+            }
+        } else {
+            RawKey {
+                index: self.nslots as u32,
+                generation: self.brand,
+            }
+        }
+    }
+    /// The row at `key`, if there is one.
+    pub fn get(&self, key: RawKey) -> Option<&T> {
+        let slot = self.slot(key.index())?;
+        if slot.generation == key.generation {
+            slot.row.as_ref()
+        } else {
+            None
+        }
+    }
+    /// Check that there is a row at `key`, which is in the named table.
+    pub fn check(&self, key: RawKey, table: &'static str) -> Result<(), crate::Error> {
+        match self.slot(key.index()) {
+            // The slot has never given out the generation of the key.
+            Some(slot) if self.age(slot.fresh) <= self.age(key.generation) => {
+                Err(crate::Error::ForeignKey { table })
+            }
+            None => Err(crate::Error::ForeignKey { table }),
+            Some(slot) if slot.generation != key.generation || slot.row.is_none() => {
+                Err(crate::Error::StaleKey { table })
+            }
+            Some(_) => Ok(()),
+        }
+    }
+    /// Iterate over the rows along with their keys.
+    pub fn iter(&self) -> impl Iterator<Item = (RawKey, &T)> {
+        self.chunks
+            .iter()
+            .flat_map(|c| c.iter())
+            .enumerate()
+            .filter_map(|(i, s)| {
+                s.row.as_ref().map(|r| {
+                    let key = RawKey {
+                        index: i as u32,
+                        generation: s.generation,
+                    };
+                    (key, r)
+                })
+            })
+    }
+
+    /// The row in each slot touched by `changes` before and after them,
+    /// along with its key, for a group of changes that ended with the
+    /// table as it is now.  A row that was removed and whose slot was then
+    /// reused appears as a pair of different keys.
+    #[allow(clippy::type_complexity)]
+    pub fn diff<'a>(
+        &'a self,
+        changes: &'a TableChanges<T>,
+    ) -> impl Iterator<Item = (Option<(RawKey, &'a T)>, Option<(RawKey, &'a T)>)> + 'a {
+        fn row<T>(index: u32, slot: Option<&Slot<T>>) -> Option<(RawKey, &T)> {
+            let slot = slot?;
+            let key = RawKey {
+                index,
+                generation: slot.generation,
+            };
+            slot.row.as_ref().map(|r| (key, r))
+        }
+        changes
+            .slots
+            .iter()
+            .map(move |(i, old)| (row(*i, old.as_ref()), row(*i, self.slot(*i as usize))))
+    }
+
+    /// How many times a slot has been reused by the time it reaches
+    /// `generation`.
+    fn age(&self, generation: u32) -> u32 {
+        generation.wrapping_sub(self.brand)
+    }
+
+    fn slot(&self, index: usize) -> Option<&Slot<T>> {
+        if index < self.nslots {
+            Some(&self.chunks[index / CHUNK][index % CHUNK])
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: Clone> Table<T> {
+    /// Add a row, returning its key.
+    pub fn insert(&mut self, row: T) -> RawKey {
+        let key = self.next_key();
+        self.touch(key.index());
+        if key.index() == self.nslots {
+            self.resize(self.nslots + 1);
+        }
+        let slot = self.slot_mut(key.index());
+        slot.row = Some(row);
+        slot.fresh = slot.generation.wrapping_add(1);
+        self.free = slot.next_free.take();
+        self.len += 1;
+        key
+    }
+    /// Remove the row at `key`, if there is one.
+    pub fn remove(&mut self, key: RawKey) -> Option<T> {
+        self.get(key)?;
+        self.touch(key.index());
+        let free = self.free.replace(key.index);
+        let slot = self.slot_mut(key.index());
+        let row = slot.row.take();
+        slot.generation = slot.fresh;
+        slot.next_free = free;
+        self.len -= 1;
+        row
+    }
+    /// The row at `key`, if there is one.
+    pub fn get_mut(&mut self, key: RawKey) -> Option<&mut T> {
+        self.get(key)?;
+        self.touch(key.index());
+        self.slot_mut(key.index()).row.as_mut()
+    }
+
+    /// Start recording changes.  Groups of changes may be nested.
+    #[doc(hidden)]
+    pub fn begin(&mut self) {
+        self.journal.push(TableChanges {
+            slots: Vec::new(),
+            touched: std::collections::HashSet::new(),
+            nslots: self.nslots,
+            free: self.free,
+            len: self.len,
+        });
+    }
+    /// Stop recording the current group of changes, folding it into the
+    /// enclosing group if there is one.
+    #[doc(hidden)]
+    pub fn commit(&mut self) {
+        let changes = self.end();
+        if let Some(parent) = self.journal.last_mut() {
+            for (i, slot) in changes.slots {
+                if parent.touched.insert(i) {
+                    parent.slots.push((i, slot));
+                }
+            }
+        }
+    }
+    /// Stop recording the current group of changes and return it.
+    #[doc(hidden)]
+    pub fn end(&mut self) -> TableChanges<T> {
+        self.journal.pop().expect("end without begin")
+    }
+    /// Undo a group of changes, returning the changes that would redo it.
+    ///
+    /// Slots that were added by the changes are kept as vacant slots, and
+    /// no slot goes back to a generation it has already given out, so that
+    /// keys for rows that are reverted away are never reused.
+    #[doc(hidden)]
+    pub fn revert(&mut self, changes: TableChanges<T>) -> TableChanges<T> {
+        // Each slot goes back to how it was before the changes, so an
+        // enclosing group has nothing to record, except that slots added
+        // by the changes were not there when it began.
+        if let Some(parent) = self.journal.last_mut() {
+            for &(i, ref slot) in changes.slots.iter() {
+                if slot.is_none() && parent.touched.insert(i) {
+                    parent.slots.push((i, None));
+                }
+            }
+        }
+        let old_nslots = self.nslots;
+        let mut inverse = TableChanges {
+            slots: Vec::with_capacity(changes.slots.len()),
+            touched: changes.touched,
+            nslots: old_nslots,
+            free: std::mem::replace(&mut self.free, changes.free),
+            len: std::mem::replace(&mut self.len, changes.len),
+        };
+        if self.nslots < changes.nslots {
+            self.resize(changes.nslots);
+        }
+        let brand = self.brand;
+        let mut added = Vec::new();
+        for (i, slot) in changes.slots {
+            if slot.is_none() {
+                added.push(i);
+            }
+            let mut slot = slot.unwrap_or_else(|| Slot::vacant(brand));
+            let fresh = self.slot(i as usize).unwrap().fresh;
+            if self.age(fresh) > self.age(slot.fresh) {
+                slot.fresh = fresh;
+            }
+            if slot.row.is_none() {
+                slot.generation = slot.fresh;
+            }
+            let current = std::mem::replace(self.slot_mut(i as usize), slot);
+            inverse.slots.push((i, Some(current)));
+        }
+        added.sort_unstable();
+        for i in added.into_iter().rev() {
+            let free = self.free.replace(i);
+            self.slot_mut(i as usize).next_free = free;
+        }
+        inverse
+    }
+
+    fn touch(&mut self, index: usize) {
+        if let Some(changes) = self.journal.last_mut() {
+            if changes.touched.insert(index as u32) {
+                let old = if index < self.nslots {
+                    Some(self.chunks[index / CHUNK][index % CHUNK].clone())
+                } else {
+                    None
+                };
+                changes.slots.push((index as u32, old));
+            }
+        }
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut Slot<T> {
+        let chunk = &mut Arc::make_mut(&mut self.chunks)[index / CHUNK];
+        &mut Arc::make_mut(chunk)[index % CHUNK]
+    }
+
+    /// Add vacant slots, or drop slots from the end, to have `nslots`.
+    fn resize(&mut self, nslots: usize) {
+        if nslots == self.nslots {
+            return;
+        }
+        let brand = self.brand;
+        let chunks = Arc::make_mut(&mut self.chunks);
+        chunks.truncate(nslots.div_ceil(CHUNK));
+        while chunks.len() * CHUNK < nslots {
+            chunks.push(Arc::new(Vec::with_capacity(CHUNK)));
+        }
+        let full = chunks.len().saturating_sub(1) * CHUNK;
+        if let Some(last) = chunks.last_mut() {
+            let n = nslots - full;
+            Arc::make_mut(last).resize_with(n, || Slot::vacant(brand));
+        }
+        for chunk in chunks.iter_mut().rev().skip(1) {
+            if chunk.len() == CHUNK {
+                break;
+            }
+            Arc::make_mut(chunk).resize_with(CHUNK, || Slot::vacant(brand));
+        }
+        self.nslots = nslots;
+    }
+}
+
+impl<T> std::ops::Index<RawKey> for Table<T> {
+    type Output = T;
+    fn index(&self, key: RawKey) -> &T {
+        self.get(key).expect("no row with this key")
+    }
+}
+
+impl<T: Clone> std::ops::IndexMut<RawKey> for Table<T> {
+    fn index_mut(&mut self, key: RawKey) -> &mut T {
+        self.get_mut(key).expect("no row with this key")
+    }
+}
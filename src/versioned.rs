@@ -0,0 +1,119 @@
+//! A database whose readers never wait for its writer.
+
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+
+use crate::Snapshot;
+
+/// A database that can take an immutable snapshot of itself, which every
+/// database generated by `schema!` can.
+pub trait Database: Sized {
+    /// An immutable view of the database as it is now.
+    fn snapshot(&self) -> Snapshot<Self>;
+}
+
+/// A published version of a database, along with its number.
+type Version<D> = (u64, Snapshot<D>);
+
+/// A database with one writer at a time, which publishes a new version
+/// each time it finishes a write, and any number of readers, each of
+/// which sees whichever version was latest when it started reading.
+///
+/// Readers never take a lock or wait for a write to finish, since
+/// publishing a version only swaps a pointer.  The writer never waits for
+/// readers to finish with a version, since a version shares the storage of
+/// the database until the writer modifies it.  It only waits for readers
+/// that are part way through taking a reference to the version it
+/// replaced, which takes a few instructions.
+///
+/// If a write panics, nothing it did is published, but every later write
+/// panics too.  Make changes within a `transaction` to have them undone
+/// instead.
+pub struct Versioned<D> {
+    writer: Mutex<D>,
+    /// The latest version, from `Arc::into_raw`.  We hold one reference to
+    /// it.
+    latest: AtomicPtr<Version<D>>,
+    /// Counts how many times the latest version has been replaced.  A
+    /// reader registers in the count of readers for the current epoch
+    /// before it loads `latest`, so that a writer can tell when no reader
+    /// can still be about to take a reference to the version it replaced.
+    epoch: AtomicU64,
+    readers: [AtomicUsize; 2],
+    _latest: std::marker::PhantomData<Arc<Version<D>>>,
+}
+
+impl<D: Database> Versioned<D> {
+    /// Start publishing versions of a database.
+    pub fn new(db: D) -> Self {
+        let latest = Arc::into_raw(Arc::new((0, db.snapshot())));
+        Versioned {
+            writer: Mutex::new(db),
+            latest: AtomicPtr::new(latest as *mut _),
+            epoch: AtomicU64::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            _latest: std::marker::PhantomData,
+        }
+    }
+    /// The latest version of the database.
+    pub fn read(&self) -> Snapshot<D> {
+        self.latest().1.clone()
+    }
+    /// The number of versions published since this was created.
+    pub fn version(&self) -> u64 {
+        self.latest().0
+    }
+    /// Wait for any other write to finish, modify the database with `f`,
+    /// and then publish the result as a new version.
+    pub fn write<R>(&self, f: impl FnOnce(&mut D) -> R) -> R {
+        let mut db = self.writer.lock().expect("a writer panicked");
+        let r = f(&mut db);
+        // Only the writer changes `latest`, so the version cannot change
+        // while we hold the lock.
+        let number = self.latest().0 + 1;
+        let new = Arc::into_raw(Arc::new((number, db.snapshot())));
+        let old = self.latest.swap(new as *mut _, SeqCst);
+        // Readers that start from now on will find the new version, so we
+        // only need to wait for those that registered before this.
+        let epoch = self.epoch.fetch_add(1, SeqCst);
+        while self.readers[epoch as usize % 2].load(SeqCst) != 0 {
+            std::thread::yield_now();
+        }
+        // Safety: `old` came from `Arc::into_raw`, and no reader can be
+        // about to take a reference to it.
+        drop(unsafe { Arc::from_raw(old) });
+        r
+    }
+
+    fn latest(&self) -> Arc<Version<D>> {
+        let readers = loop {
+            let epoch = self.epoch.load(SeqCst);
+            let readers = &self.readers[epoch as usize % 2];
+            readers.fetch_add(1, SeqCst);
+            // If a writer moved on to a new epoch before we registered, it
+            // might not wait for us, so we register again.
+            if self.epoch.load(SeqCst) == epoch {
+                break readers;
+            }
+            readers.fetch_sub(1, SeqCst);
+        };
+        let latest = self.latest.load(SeqCst);
+        // Safety: `latest` came from `Arc::into_raw`, and the writer that
+        // replaces it waits for us to deregister before releasing its
+        // reference.
+        let latest = unsafe {
+            Arc::increment_strong_count(latest);
+            Arc::from_raw(latest)
+        };
+        readers.fetch_sub(1, SeqCst);
+        latest
+    }
+}
+
+impl<D> Drop for Versioned<D> {
+    fn drop(&mut self) {
+        // Safety: `latest` came from `Arc::into_raw`, and we hold a
+        // reference to it.
+        drop(unsafe { Arc::from_raw(*self.latest.get_mut()) });
+    }
+}
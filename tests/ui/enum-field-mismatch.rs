@@ -0,0 +1,15 @@
+polygraph::schema!{
+    type Tree;
+    pub struct Person(String);
+    pub enum Event {
+        Birth {
+            who: Key<Person>,
+        },
+        Party {
+            who: KeySet<Person>,
+        },
+    }
+}
+
+fn main() {
+}
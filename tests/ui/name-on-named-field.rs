@@ -0,0 +1,11 @@
+polygraph::schema!{
+    type Tree;
+    pub struct Person(String);
+    pub struct Dog {
+        #[polygraph(name = "owner")]
+        pub person: Key<Person>,
+    }
+}
+
+fn main() {
+}
@@ -0,0 +1,10 @@
+polygraph::schema!{
+    type Tree;
+    pub struct Person(String);
+    pub struct Dog {
+        pub walkers: KeyMap<Person, (String, Box<Key<Person>>)>,
+    }
+}
+
+fn main() {
+}
@@ -0,0 +1,10 @@
+polygraph::schema!{
+    type Tree;
+    pub struct Person(String);
+    pub struct Dog {
+        pub owners: Option<KeySet<Person>>,
+    }
+}
+
+fn main() {
+}
@@ -0,0 +1,11 @@
+mod tree {
+    polygraph::schema!{
+        type Tree;
+        pub struct Surname(pub String);
+    }
+}
+
+fn main() {
+    let db = tree::Tree::new();
+    db.surname.len();
+}
@@ -0,0 +1,12 @@
+polygraph::schema!{
+    type Tree;
+    pub struct Surname(String);
+    pub struct Person {
+        #[polygraph(on_delete = "set_null")]
+        surname: Key<Surname>,
+        name: String,
+    }
+}
+
+fn main() {
+}
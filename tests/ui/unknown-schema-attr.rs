@@ -0,0 +1,8 @@
+polygraph::schema!{
+    #[polygraph(brand)]
+    type Tree;
+    pub struct Surname(String);
+}
+
+fn main() {
+}